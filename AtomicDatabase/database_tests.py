@@ -1,4 +1,6 @@
 import unittest
+import os
+import tempfile
 import eav_database as e
 
 class TestEAVDatabase(unittest.TestCase):
@@ -45,6 +47,178 @@ class TestEAVDatabase(unittest.TestCase):
             "args": ["A", "B"]
         })
 
+    def test_add_rule_text(self):
+        self.db.add_rule_text("parent", ["P", "C"], "(| (C father P) (C mother P))")
+        self.assertEqual(self.db.rules["parent"]["text"], "(| (C father P) (C mother P))")
+        self.assertEqual(list(e.evaluate_rule(self.db, e.body('(P parent "cool@gmail.com")')[0])),
+                         [{ 'P': "pa_cool@gmail.com" }, { 'P': "mam_cool@gmail.com" }])
+
+        with self.assertRaises(ValueError):
+            self.db.add_rule_text("broken", ["P"], "(P father")
+        self.assertFalse("broken" in self.db.rules)
+
+    def test_check_stratification(self):
+        self.assertEqual(sorted(name for stratum in self.db.check_stratification() for name in stratum),
+                         sorted(self.db.rules))
+
+        self.db.add_rule_text("fatherless", ["X", "Y"], "(- (X age Y) (X grandfather G))")
+        strata = self.db.check_stratification()
+        self.assertLess(strata.index(["grandfather"]), strata.index(["fatherless"]))
+
+        self.db.add_rule_text("p", ["X", "Y"], "(- (X age Y) (X q Y))")
+        self.db.add_rule_text("q", ["X", "Y"], "(X p Y)")
+        with self.assertRaises(ValueError):
+            self.db.check_stratification()
+        self.db.remove_rule("p")
+        self.db.remove_rule("q")
+
+        self.db.add_rule_text("orphan", ["X", "Y"], "(? (X father Y) (X orphan Y))")
+        with self.assertRaises(ValueError):
+            self.db.check_stratification()
+        self.db.add_rule_text("orphan", ["X", "Y"], "(? (X orphan Y) (X father Y))")
+        self.db.check_stratification()
+        self.db.remove_rule("orphan")
+
+        self.db.add_rule_text("kids", ["X", "N"], "(count N (C kids X))")
+        with self.assertRaises(ValueError):
+            self.db.check_stratification()
+        self.db.add_rule_text("kids", ["X", "N"], "(sum A N (C kids X) (C age A))")
+        with self.assertRaises(ValueError):
+            self.db.check_stratification()
+        self.db.add_rule_text("kids", ["X", "L"], "(collect C L (C kids X))")
+        with self.assertRaises(ValueError):
+            self.db.check_stratification()
+        self.db.add_rule_text("kids", ["X", "N"], "(count N (C father X))")
+        self.db.check_stratification()
+
+    def test_duplicate_rules(self):
+        self.assertEqual(self.db.duplicate_rules(), [])
+
+        self.db.add_rule_text("paternal_grandfather", ["Kid", "Old"], "(Kid father Mid)\n(Mid father Old)")
+        self.db.add_rule_text("not_a_copy", ["P", "G"], "(P father X)\n(X mother G)")
+        self.db.add_rule_text("swapped_args", ["G", "P"], "(P father X)\n(X father G)")
+        self.db.add_rule("empty1")
+        self.db.add_rule("empty2")
+        self.assertEqual(self.db.duplicate_rules(), ["paternal_grandfather"])
+
+        self.db.add_rule_text("g1", ["P", "N"], "(P name N)", guard_text="(P age A) (> A 57)")
+        self.db.add_rule_text("g2", ["P", "N"], "(P name N)", guard_text="(P age A) (> A 50)")
+        self.db.add_rule_text("g3", ["Q", "M"], "(Q name M)", guard_text="(Q age B) (> B 57)")
+        self.db.add_rule_text("next1", ["A", "B"], "(= B {A + 1})")
+        self.db.add_rule_text("next2", ["B", "A"], "(= A {A + 1})")
+        self.db.add_rule_text("next3", ["X", "Y"], "(= Y {X + 1})")
+        # next1 is a copy of add1 from the test database.
+        self.assertEqual(self.db.duplicate_rules(), ["paternal_grandfather", "g3", "next1", "next3"])
+
+    def test_negative_facts(self):
+        self.db.add_negative(("cool@gmail.com", "father", "pa_cool@gmail.com"))
+        self.db.add_negative(("cool@gmail.com", "grandfather", "papa_cool@gmail.com"))
+        self.db.add_negative(("nobody@gmail.com", "name", "Nobody"))
+
+        self.assertEqual(self.db.get_value("cool@gmail.com", "father"), "pa_cool@gmail.com")
+        self.assertEqual(list(e.evaluate_rule(self.db, e.body('("cool@gmail.com" father F)')[0])), [])
+        self.assertEqual(list(e.evaluate_rule(self.db, e.body('("cool@gmail.com" father "pa_cool@gmail.com")')[0])), [])
+
+        self.assertEqual(list(e.evaluate_rule(self.db, e.body('("nobody@gmail.com" name "Nobody")')[0])), [])
+        self.assertFalse("nobody@gmail.com" in self.db.entities)
+
+        self.db.remove_negative(("cool@gmail.com", "father", "pa_cool@gmail.com"))
+        self.assertEqual(list(e.evaluate_rule(self.db, e.body('("cool@gmail.com" father F)')[0])),
+                         [{ 'F': "pa_cool@gmail.com" }])
+        self.assertEqual(list(e.evaluate_rule(self.db, e.body('("cool@gmail.com" grandfather G)')[0])), [])
+        self.assertEqual(list(e.evaluate_rule(self.db, e.body('("stop@gmail.com" grandfather G)')[0])),
+                         [{ 'G': "papa_cool@gmail.com" }])
+
+        # A negative true doesn't hide a 1, and negatives survive reloading.
+        self.db.add(("cool@gmail.com", "flag", 1))
+        self.db.add_negative(("cool@gmail.com", "flag", True))
+        self.assertEqual(list(e.query(self.db, '("cool@gmail.com" flag F)')), [{ 'F': 1 }])
+        self.db.add_negative(("cool@gmail.com", "flag", 1))
+        self.assertEqual(list(e.query(self.db, '("cool@gmail.com" flag F)')), [])
+        directory = tempfile.TemporaryDirectory()
+        self.addCleanup(directory.cleanup)
+        e.save_to_file(self.db, os.path.join(directory.name, "db.json"))
+        reloaded = e.load_from_file(os.path.join(directory.name, "db.json"))
+        self.assertEqual(list(e.query(reloaded, '("cool@gmail.com" flag F)')), [])
+        self.assertTrue(reloaded.is_negative("cool@gmail.com", "grandfather", "papa_cool@gmail.com"))
+        reloaded.remove_negative(("cool@gmail.com", "flag", 1))
+        self.assertEqual(list(e.query(reloaded, '("cool@gmail.com" flag F)')), [{ 'F': 1 }])
+        self.assertEqual(len(reloaded.negatives), 3)
+
+    def test_exclusions(self):
+        self.db.add(("cool@gmail.com", "alive", "yes"))
+        self.db.add_exclusion('(X alive "yes") (X deceased "yes")')
+
+        og_entities, og_attributes, og_eavs = list(self.db.entities), list(self.db.attributes), dict(self.db.eavs)
+        with self.assertRaises(ValueError):
+            self.db.add(("cool@gmail.com", "deceased", "yes"))
+        self.assertEqual(self.db.entities, og_entities)
+        self.assertEqual(self.db.attributes, og_attributes)
+        self.assertEqual(self.db.eavs, og_eavs)
+
+        self.db.add(("stop@gmail.com", "deceased", "yes"))
+        with self.assertRaises(ValueError):
+            self.db.add(("stop@gmail.com", "alive", "yes"))
+        self.assertIsNone(self.db.get_value("stop@gmail.com", "alive"))
+
+        with self.assertRaises(ValueError):
+            self.db.add_exclusion('(X alive "yes") (X name N)')
+        self.assertEqual(len(self.db.exclusions), 1)
+
+        # Ground goals are checked against the facts, not added to them.
+        self.db.add_exclusion('("pa_cool@gmail.com" alive "yes") ("pa_cool@gmail.com" deceased "yes")')
+        self.assertIsNone(self.db.get_value("pa_cool@gmail.com", "alive"))
+        self.assertIsNone(self.db.get_value("pa_cool@gmail.com", "deceased"))
+        self.db.add(("pa_cool@gmail.com", "deceased", "yes"))
+        with self.assertRaises(ValueError):
+            self.db.add(("pa_cool@gmail.com", "alive", "yes"))
+        self.assertIsNone(self.db.get_value("pa_cool@gmail.com", "alive"))
+        with self.assertRaises(ValueError):
+            self.db.add_exclusion('("cool@gmail.com" alive "yes") ("cool@gmail.com" name N)')
+
+    def test_maintained_aggregates(self):
+        count = self.db.maintain_aggregate("count", "age")
+        total = self.db.maintain_aggregate("sum", "age")
+        oldest = self.db.maintain_aggregate("max", "age")
+        youngest = self.db.maintain_aggregate("min", "age")
+        heights = self.db.maintain_aggregate("max", "height")
+        self.assertEqual((count.value(), total.value(), oldest.value(), youngest.value()), (4, 226, 59, 53))
+        self.assertIsNone(heights.value())
+
+        self.db.add(("cool@gmail.com", "age", 20))
+        self.db.add(("stop@gmail.com", "age", 59))
+        self.assertEqual((count.value(), total.value(), oldest.value(), youngest.value()), (6, 305, 59, 20))
+        # Replacing a value takes the old one out.
+        self.db.add(("cool@gmail.com", "age", 21))
+        self.assertEqual((count.value(), total.value(), youngest.value()), (6, 306, 21))
+
+        # Retracting an extreme finds the next one, but only once no copy of it is left.
+        self.db.remove_value("mammam_cool@gmail.com", "age")
+        self.assertEqual(oldest.value(), 59)
+        self.assertTrue(self.db.remove_fact(("stop@gmail.com", "age", 59)))
+        self.assertEqual(oldest.value(), 58)
+        self.db.remove_value("cool@gmail.com", "age")
+        self.assertEqual((count.value(), total.value(), oldest.value(), youngest.value()), (3, 167, 58, 53))
+        self.assertFalse(self.db.remove_fact(("pamam_cool@gmail.com", "age", 1)))
+        self.assertEqual(count.value(), 3)
+
+        self.db.add_alias("years", "age")
+        self.db.add(("stop@gmail.com", "years", 70))
+        self.assertEqual((count.value(), oldest.value()), (4, 70))
+        self.assertEqual(self.db.maintain_aggregate("sum", "years").value(), total.value())
+
+        # Facts a failed add or load_json put in don't stay counted.
+        self.db.add_exclusion('(X age 99) (X name N)')
+        with self.assertRaises(ValueError):
+            self.db.add(("papa_cool@gmail.com", "age", 99))
+        with self.assertRaises(ValueError):
+            self.db.load_json([{ "entity": "new@gmail.com", "age": 30 }, { "entity": "kid@gmail.com", "age": 10 }])
+        self.assertEqual((count.value(), total.value(), youngest.value()), (4, 237, 53))
+        self.db.add(("cool@gmail.com", "height", 1.8))
+        self.assertEqual(heights.value(), 1.8)
+        with self.assertRaises(ValueError):
+            self.db.maintain_aggregate("avg", "age")
+
     def test_get_or_add_entity_id(self):
         og_len = len(self.db.entities)
         ide = self.db.get_or_add_entity_id("cool@gmail.com")
@@ -90,6 +264,218 @@ class TestEAVDatabase(unittest.TestCase):
         self.assertEqual(self.db.get_value("cool@gmail.com", "name"), "Joe Cool")
         self.assertIsNone(self.db.get_value("cool@gmail.com", "boogaloo"))
 
+    def test_to_dot(self):
+        dot = self.db.to_dot(["father", "listy"]).split("\n")
+        self.assertEqual(dot[0], "digraph {")
+        self.assertEqual(dot[-1], "}")
+        self.assertIn('    "cool@gmail.com";', dot)
+        self.assertIn('    "pa_cool@gmail.com";', dot)
+        self.assertIn('    "cool@gmail.com" -> "pa_cool@gmail.com" [label="father"];', dot)
+        self.assertIn('    "papa_cool@gmail.com" -> "blarg_cool" [label="father"];', dot)
+        self.assertIn('    // skipped list value of listy for blarg_cool', dot)
+        self.assertFalse(any("mother" in line for line in dot))
+        self.assertEqual(len([line for line in dot if "->" in line]), 5)
+
+    def test_load_json(self):
+        added = self.db.load_json([{
+            "entity": "ann@gmail.com",
+            "name": "Ann Cool",
+            "age": 30,
+            "listy": [1, 2, 3],
+            "scores": [1, 2.5, [3, "x"]],
+            "address": { "city": "Paris", "zip": "75001" }
+        }])
+        self.assertEqual(added, 7)
+        self.assertEqual(self.db.get_value("ann@gmail.com", "age"), 30)
+        self.assertEqual(self.db.get_value("ann@gmail.com", "scores"),
+                         [(e.LITERAL, 1), (e.LITERAL, 2.5), (e.LIST, [(e.LITERAL, 3), (e.LITERAL, "x")])])
+        self.assertEqual(self.db.get_value("ann@gmail.com", "address"), "ann@gmail.com/address")
+        self.assertEqual(self.db.get_value("ann@gmail.com/address", "city"), "Paris")
+        self.assertEqual(list(e.evaluate_rule(self.db, e.body("(P address A) (A zip Z)")[0])),
+                         [{ 'P': "ann@gmail.com", 'A': "ann@gmail.com/address", 'Z': "75001" }])
+
+        with self.assertRaises(ValueError):
+            self.db.load_json([{ "entity": "bob@gmail.com", "alive": None }])
+        with self.assertRaises(ValueError):
+            self.db.load_json([{ "name": "No Entity" }])
+        with self.assertRaises(ValueError):
+            self.db.load_json({ "entity": "bob@gmail.com" })
+        self.assertFalse("bob@gmail.com" in self.db.entities)
+
+        self.db.load_json([{ "entity": "bob@gmail.com", "alive": True }])
+        self.assertIs(self.db.get_value("bob@gmail.com", "alive"), True)
+
+        entities, eavs = list(self.db.entities), dict(self.db.eavs)
+        with self.assertRaises(ValueError):
+            self.db.load_json([{ "entity": "a@gmail.com", "name": "A" }, { "entity": "b@gmail.com", "age": 4 }])
+        self.assertEqual(self.db.entities, entities)
+        self.assertEqual(self.db.eavs, eavs)
+
+        with self.assertRaises(ValueError):
+            self.db.add(("c@gmail.com", "age", 4))
+        self.assertFalse("c@gmail.com" in self.db.entities)
+
+        with self.assertRaises(ValueError):
+            self.db.load_json([{ "entity": "kid@gmail.com", "age": 4 }])
+
+    def test_aliases(self):
+        self.db.add_alias("dad", "father")
+        self.db.add_alias("papa", "dad")
+        self.db.add_alias("gramps", "grandfather")
+        self.assertEqual(list(e.evaluate_rule(self.db, e.body('("cool@gmail.com" papa F)')[0])),
+                         [{ 'F': "pa_cool@gmail.com" }])
+        self.assertEqual(list(e.evaluate_rule(self.db, e.body('("cool@gmail.com" gramps G)')[0])),
+                         [{ 'G': "papa_cool@gmail.com" }])
+
+        with self.assertRaises(ValueError):
+            self.db.add_alias("father", "papa")
+        self.assertFalse("father" in self.db.aliases)
+        with self.assertRaises(ValueError):
+            self.db.add_alias("name", "age")
+        with self.assertRaises(ValueError):
+            self.db.add_alias("grandfather", "father")
+        self.db.add_alias("pop", "pops")
+        with self.assertRaises(ValueError):
+            self.db.add_alias("pops", "pop")
+        self.assertFalse("pops" in self.db.aliases)
+
+        self.db.add(("kid@gmail.com", "dad", "cool@gmail.com"))
+        self.assertNotIn("dad", self.db.attributes)
+        self.assertEqual(self.db.get_value("kid@gmail.com", "father"), "cool@gmail.com")
+        self.assertEqual(self.db.get_value("kid@gmail.com", "papa"), "cool@gmail.com")
+        self.assertEqual(list(e.query(self.db, '("kid@gmail.com" dad F)')), [{ 'F': "cool@gmail.com" }])
+        self.assertEqual(list(e.query(self.db, '("kid@gmail.com" father F)')), [{ 'F': "cool@gmail.com" }])
+        self.db.remove_value("kid@gmail.com", "papa")
+        self.assertIsNone(self.db.get_value("kid@gmail.com", "father"))
+
+        self.assertEqual(self.db.suggest_values("dad"), self.db.suggest_values("father"))
+        self.assertNotEqual(self.db.suggest_values("dad"), [])
+        self.assertEqual(list(self.db.get_entities_values("papa")), list(self.db.get_entities_values("father")))
+        self.assertEqual(self.db.sccs("dad"), self.db.sccs("father"))
+        self.assertEqual(self.db.to_dot(["dad"]), self.db.to_dot(["father"]))
+        self.assertEqual(self.db.is_functional(["papa"], ["name"]), self.db.is_functional(["father"], ["name"]))
+        self.db.add_negative(("cool@gmail.com", "dad", "pa_cool@gmail.com"))
+        self.assertEqual(list(e.query(self.db, '("cool@gmail.com" father F)')), [])
+        self.db.remove_negative(("cool@gmail.com", "papa", "pa_cool@gmail.com"))
+        self.assertEqual(list(e.query(self.db, '("cool@gmail.com" father F)')), [{ 'F': "pa_cool@gmail.com" }])
+
+    def test_from_parts(self):
+        db = e.from_parts({
+            "father": [("kid@gmail.com", "dad@gmail.com"), ("dad@gmail.com", "granddad@gmail.com")],
+            "age": [("dad@gmail.com", 40)]
+        }, {
+            "grandfather": (["X", "G"], "(X father F) (F father G)")
+        }, { "age": self.db.attribute_metadata["age"] })
+        self.assertEqual(list(e.query(db, "(X grandfather G)")), [{ 'X': "kid@gmail.com", 'G': "granddad@gmail.com" }])
+        self.assertEqual(db.get_value("dad@gmail.com", "age"), 40)
+
+        with self.assertRaises(ValueError):
+            e.from_parts({ "age": [("dad@gmail.com", 4)] }, attribute_metadata={ "age": self.db.attribute_metadata["age"] })
+        with self.assertRaises(ValueError):
+            e.from_parts({ "father": [("kid@gmail.com",)] })
+        with self.assertRaises(TypeError):
+            e.from_parts({ 3: [("kid@gmail.com", "dad@gmail.com")] })
+        with self.assertRaises(ValueError):
+            e.from_parts({}, { "parent": (["X", "x"], "(X father x)") })
+        with self.assertRaises(ValueError):
+            e.from_parts({}, { "parent": (["X"], "(X father") })
+        with self.assertRaises(ValueError):
+            e.from_parts({ "father": [] , "age": [("a@gmail.com", 20)] }, { "age": (["X", "A"], "(= A 1)") })
+
+    def test_binary_round_trip(self):
+        for i in range(500):
+            self.db.add(("entity" + str(i), "age", 18 + i % 50))
+        directory = tempfile.TemporaryDirectory()
+        self.addCleanup(directory.cleanup)
+        binary, text = os.path.join(directory.name, "db.bin"), os.path.join(directory.name, "db.json")
+        e.save_to_binary(self.db, binary)
+        e.save_to_file(self.db, text)
+
+        loaded = e.load_from_binary(binary)
+        self.assertEqual(loaded.__dict__, e.load_from_file(text).__dict__)
+        self.assertEqual(loaded.saved_fields().keys(), self.db.saved_fields().keys())
+        query = e.body("(X grandfather G) (X age A)")[0]
+        self.assertEqual(list(e.evaluate_rule(loaded, query)), list(e.evaluate_rule(self.db, query)))
+        query = e.body("(X age A)")[0]
+        self.assertEqual(list(e.evaluate_rule(loaded, query)), list(e.evaluate_rule(self.db, query)))
+        self.assertLess(os.path.getsize(binary), os.path.getsize(text))
+
+    def test_sccs(self):
+        components = self.db.sccs("father")
+        self.assertTrue(all(len(c) == 1 for c in components))
+        self.assertIn(["blarg_cool"], components)
+
+        for (a, b) in [("cool@gmail.com", "stop@gmail.com"), ("stop@gmail.com", "pa_cool@gmail.com"),
+                       ("pa_cool@gmail.com", "cool@gmail.com"), ("papa_cool@gmail.com", "pa_cool@gmail.com")]:
+            self.db.add((a, "knows", b))
+        components = sorted(sorted(c) for c in self.db.sccs("knows"))
+        self.assertEqual(components, [["cool@gmail.com", "pa_cool@gmail.com", "stop@gmail.com"],
+                                      ["papa_cool@gmail.com"]])
+
+    def test_add_malformed_fact(self):
+        entities, attributes = list(self.db.entities), list(self.db.attributes)
+        with self.assertRaises(ValueError):
+            self.db.add(())
+        with self.assertRaises(ValueError):
+            self.db.add(("cool@gmail.com",))
+        with self.assertRaises(TypeError):
+            self.db.add(("new@gmail.com", 3, "foo"))
+        self.assertEqual(self.db.entities, entities)
+        self.assertEqual(self.db.attributes, attributes)
+
+    def test_remove_fact(self):
+        self.assertFalse(self.db.remove_fact(("papa_cool@gmail.com", "age", 57)))
+        self.assertFalse(self.db.remove_fact(("nobody", "age", 57)))
+        self.assertTrue(self.db.remove_fact(("papa_cool@gmail.com", "age", 56)))
+        self.assertEqual(list(e.query(self.db, "(X age 56)")), [])
+        self.assertFalse(self.db.remove_fact(("papa_cool@gmail.com", "age", 56)))
+        self.assertIn("papa_cool@gmail.com", self.db.entities)
+
+        self.assertTrue(self.db.remove_rule("grandfather"))
+        self.assertFalse(self.db.remove_rule("grandfather"))
+        self.assertNotIn("grandfather", self.db.rules)
+
+    def test_suggest_values(self):
+        self.assertEqual(self.db.suggest_values("age"), [53, 56, 58, 59])
+        self.db.add(("cool@gmail.com", "age", 100))
+        self.assertEqual(self.db.suggest_values("age"), [53, 56, 58, 59, 100])
+        self.assertEqual(self.db.suggest_values("father", "papa"), ["papa_cool@gmail.com"])
+        self.assertEqual(self.db.suggest_values("age", "5"), [])
+        self.assertEqual(self.db.suggest_values("age", "mam", 0),
+                         ["mammam_cool@gmail.com", "mampa_cool@gmail.com"])
+        self.assertEqual(self.db.suggest_values("height"), [])
+
+    def test_diff(self):
+        other = e.load_from_file("../test.db.json")
+        self.assertEqual(self.db.diff(other), { "added": {}, "removed": {}, "rules_added": [],
+                                                "rules_removed": [], "rules_changed": [] })
+
+        other.add(("cool@gmail.com", "age", 20))
+        other.remove_value("papa_cool@gmail.com", "age")
+        other.add_rule_text("parent", ["X", "P"], "(| (X father P) (X mother P))")
+        other.add_rule_text("grandfather", ["X", "G"], "(X father G)")
+        del other.rules["crash"]
+
+        diff = self.db.diff(other)
+        self.assertEqual(diff["added"], { "age": [("cool@gmail.com", 20)] })
+        self.assertEqual(diff["removed"], { "age": [("papa_cool@gmail.com", 56)] })
+        self.assertEqual(diff["rules_added"], ["parent"])
+        self.assertEqual(diff["rules_removed"], ["crash"])
+        self.assertEqual(diff["rules_changed"], ["grandfather"])
+        self.assertEqual(other.diff(self.db)["added"], diff["removed"])
+
+        other.add_rule_text("add1", ["I", "O"], "(= O {I + 1})", guard_text="(> I 0)")
+        self.assertEqual(self.db.diff(other)["rules_changed"], ["grandfather", "add1"])
+
+    def test_is_functional(self):
+        self.assertTrue(self.db.is_functional(["name"], ["father", "mother"]))
+        self.assertTrue(self.db.is_functional(["father", "mother"], ["father"]))
+        self.assertFalse(self.db.is_functional(["father"], ["mother"]))
+
+        self.db.add(("stop@gmail.com", "listy", [(e.LITERAL, 1)]))
+        self.db.add(("cool@gmail.com", "listy", [(e.LITERAL, 1)]))
+        self.assertFalse(self.db.is_functional(["listy"], ["name"]))
+
 
 class TestSELEngine(unittest.TestCase):
     def test_unification_with_variables(self):
@@ -130,6 +516,63 @@ class TestSELEngine(unittest.TestCase):
                                   [(e.LIST, [(e.LITERAL, 1), (e.VARIABLE, 'Z'), (e.VARIABLE, 'Z')])],
                                   {}))
 
+    def test_unification_with_fixed_length_lists(self):
+        self.assertEqual(e.unify([(e.LIST, [(e.VARIABLE, 'X'), (e.VARIABLE, 'Y')])],
+                                 [(e.LIST, [(e.LITERAL, 1), (e.LITERAL, 2)])], {}), { 'X': 1, 'Y': 2 })
+        self.assertIsNone(e.unify([(e.LIST, [(e.VARIABLE, 'X'), (e.VARIABLE, 'Y')])],
+                                  [(e.LIST, [(e.LITERAL, 1), (e.LITERAL, 2), (e.LITERAL, 3)])], {}))
+        self.assertIsNone(e.unify([(e.LIST, [(e.VARIABLE, 'X'), (e.VARIABLE, 'Y'), (e.VARIABLE, 'Z')])],
+                                  [(e.LIST, [(e.LITERAL, 1), (e.LITERAL, 2)])], {}))
+
+        bound = { 'L': [(e.LITERAL, 1), (e.LITERAL, 2), (e.LITERAL, 3)] }
+        self.assertIsNone(e.unify([(e.LIST, [(e.VARIABLE, 'X'), (e.VARIABLE, 'Y')])],
+                                  [(e.VARIABLE, 'L')], dict(bound)))
+
+    def test_unification_occurs_check(self):
+        self.assertIsNone(e.unify([(e.VARIABLE, 'X')], [(e.LIST, [(e.VARIABLE, 'X')])], {}))
+        self.assertIsNone(e.unify([(e.LIST, [(e.LITERAL, 1), (e.LIST, [(e.VARIABLE, 'X')])])],
+                                  [(e.VARIABLE, 'X')], {}))
+        self.assertEqual(e.unify([(e.VARIABLE, 'X')], [(e.LIST, [(e.VARIABLE, 'Y')])], {}),
+                         { 'X': [(e.VARIABLE, 'Y')] })
+
+        # Through a variable bound to a list, a linked variable or destructuring.
+        self.assertIsNone(e.unify([(e.VARIABLE, 'X')], [(e.VARIABLE, 'Y')], { 'Y': [(e.VARIABLE, 'X')] }))
+        self.assertIsNone(e.unify([(e.VARIABLE, 'Y')], [(e.VARIABLE, 'X')], { 'Y': [(e.VARIABLE, 'X')] }))
+        self.assertIsNone(e.unify([(e.VARIABLE, 'X')], [(e.LIST, [(e.VARIABLE, 'Z')])], { 'Z': (e.VARIABLE, 'X') }))
+        pattern = (e.LIST, [(e.VARIABLE, 'H'), (e.VARIABLE, '...'), (e.VARIABLE, 'T')])
+        self.assertIsNone(e.unify([pattern], [(e.VARIABLE, 'L')], { 'L': [(e.LITERAL, 1), (e.VARIABLE, 'T')] }))
+        self.assertEqual(e.unify([pattern], [(e.VARIABLE, 'L')], { 'L': [(e.LITERAL, 1), (e.LITERAL, 2)] }),
+                         { 'L': [(e.LITERAL, 1), (e.LITERAL, 2)], 'T': [(e.LITERAL, 2)], 'H': 1 })
+
+    def test_resolve(self):
+        binds = { 'X': [(e.VARIABLE, 'Y')], 'Y': [(e.LITERAL, 1), (e.VARIABLE, 'Z')], 'Z': 2 }
+        self.assertEqual(e.resolve(binds['X'], binds), [(e.LIST, [(e.LITERAL, 1), (e.LITERAL, 2)])])
+        self.assertEqual(e.resolve_binds(binds), {
+            'X': [(e.LIST, [(e.LITERAL, 1), (e.LITERAL, 2)])],
+            'Y': [(e.LITERAL, 1), (e.LITERAL, 2)],
+            'Z': 2
+        })
+
+        self.assertEqual(e.resolve([(e.LIST, [(e.VARIABLE, 'Z'), (e.VARIABLE, 'W')])], binds),
+                         [(e.LIST, [(e.LITERAL, 2), (e.VARIABLE, 'W')])])
+
+        cyclic = { 'A': [(e.VARIABLE, 'B')], 'B': [(e.VARIABLE, 'A')] }
+        self.assertEqual(e.resolve_binds(cyclic)['A'], [(e.LIST, [(e.VARIABLE, 'A')])])
+
+        # Variables unified with each other are linked, and resolve to the
+        # value at the end of the chain or to its last (unbound) variable.
+        binds = { 'A': (e.VARIABLE, 'B'), 'B': (e.VARIABLE, 'C'), 'C': [(e.VARIABLE, 'D'), (e.LIST, [(e.VARIABLE, 'A')])],
+                  'D': (e.VARIABLE, 'E') }
+        self.assertEqual(e.resolve(binds['A'], binds), [(e.VARIABLE, 'E'), (e.LIST, [(e.VARIABLE, 'C')])])
+        self.assertEqual(e.resolve_binds(binds)['D'], (e.VARIABLE, 'E'))
+        self.assertIsNone(e.get_binds('D', binds, {}))
+        self.assertEqual(e.resolve((e.VARIABLE, 'X'), { 'X': (e.VARIABLE, 'Y'), 'Y': (e.VARIABLE, 'X') }), (e.VARIABLE, 'X'))
+
+        binds = e.unify([(e.VARIABLE, 'X')], [(e.VARIABLE, 'Y')], {})
+        binds = e.unify([(e.VARIABLE, 'Y')], [(e.VARIABLE, 'Z')], binds)
+        binds = e.unify([(e.VARIABLE, 'Z')], [(e.LIST, [(e.LITERAL, 1)])], binds)
+        self.assertEqual(e.resolve_binds(binds), { 'X': [(e.LITERAL, 1)], 'Y': [(e.LITERAL, 1)], 'Z': [(e.LITERAL, 1)] })
+
     def test_unification_with_destructuring(self):
         pass
 
@@ -139,5 +582,713 @@ class TestSELEngine(unittest.TestCase):
     def test_evaluation(self):
         pass
 
+
+def solution_set(solutions, variables):
+    return { tuple(e.freeze(binds.get(var)) for var in variables) for binds in solutions }
+
+class TestQueries(unittest.TestCase):
+    def setUp(self):
+        self.db = e.load_from_file("../test.db.json")
+
+    def assertSolutionsEqual(self, actual, expected, variables):
+        self.assertEqual(solution_set(actual, variables), solution_set(expected, variables))
+
+    def holds(self, query):
+        return list(e.evaluate_rule(self.db, e.body(query)[0])) != []
+
+    def test_assert_solutions_equal(self):
+        fathers = list(e.evaluate_rule(self.db, e.body("(X father F)")[0]))
+        self.assertSolutionsEqual(fathers, list(reversed(fathers)), ['X', 'F'])
+        self.assertSolutionsEqual([{ 'X': 1, 'Helper': 2 }], [{ 'X': 1, 'Helper': 3 }], ['X'])
+        self.assertSolutionsEqual([{ 'L': [(e.LITERAL, 1)] }], [{ 'L': [(e.LITERAL, 1)] }], ['L'])
+        with self.assertRaises(AssertionError):
+            self.assertSolutionsEqual(fathers, fathers[1:], ['X', 'F'])
+
+    def test_query_fold(self):
+        fathers = e.body("(X father Y)")[0]
+        self.assertEqual(e.query_fold(self.db, fathers, 0, lambda n, _: n + 1), 5)
+
+        def histogram(acc, binds):
+            acc[binds['Y']] = acc.get(binds['Y'], 0) + 1
+            return acc
+        self.assertEqual(e.query_fold(self.db, fathers, {}, histogram), {
+            "pa_cool@gmail.com": 2,
+            "papa_cool@gmail.com": 1,
+            "pamam_cool@gmail.com": 1,
+            "blarg_cool": 1
+        })
+
+    def test_rule_guard(self):
+        # The swapped-in body raises on the undefined `Z` if it is ever evaluated.
+        self.db.add_rule("elder_name", ["P", "N"], new_rule={
+            "lang": 0,
+            "text": "(P name N)",
+            "guard": e.body("(P age A) (> A 57)")[0],
+            "body": e.body("(P name N)")[0]
+        })
+        self.assertEqual(list(e.evaluate_rule(self.db, e.body('("pamam_cool@gmail.com" elder_name N)')[0])),
+                         [{ 'N': "Ed Cool" }])
+
+        self.db.rules["elder_name"]["body"] = e.body("(P name N) (< Z 1)")[0]
+        self.assertEqual(list(e.evaluate_rule(self.db, e.body('("papa_cool@gmail.com" elder_name N)')[0])), [])
+
+        self.db.add_rule_text("tall_name", ["P", "N"], "(P name N)", guard_text='("cool@gmail.com" height 3)')
+        self.assertEqual(list(e.query(self.db, '("cool@gmail.com" tall_name N)')), [])
+        self.assertIsNone(self.db.get_value("cool@gmail.com", "height"))
+
+    def test_rule_guard_survives_resaving(self):
+        self.db.add_rule_text("elder_name", ["P", "N"], "(P name N)", guard_text="(P age A) (> A 57)")
+        query = e.body('("papa_cool@gmail.com" elder_name N)')[0]
+        self.assertEqual(list(e.evaluate_rule(self.db, query)), [])
+
+        # What the rules window does with every rule it draws.
+        rule = self.db.rules["elder_name"]
+        self.db.add_rule("elder_name", ["P", "N"], { "lang": 0, "text": rule["text"], "body": rule["body"] })
+        self.assertEqual(list(e.evaluate_rule(self.db, query)), [])
+        self.db.add_rule_text("elder_name", ["P", "N"], "(P name N)")
+        self.assertEqual(list(e.evaluate_rule(self.db, query)), [])
+
+        self.db.add_rule_text("elder_name", ["P", "N"], "(P name N)", guard_text="")
+        self.assertEqual(len(list(e.evaluate_rule(self.db, query))), 1)
+
+    def test_conjugation_arity(self):
+        def run(query):
+            return list(e.evaluate_rule(self.db, e.body(query)[0]))
+
+        with self.assertRaisesRegex(ValueError, "Not enough elements in INTERSECTION Conjugation"):
+            run("(^ (X father F))")
+        with self.assertRaisesRegex(ValueError, "Too many elements in DIFFERENCE Conjugation"):
+            run("(- (X father F) (X age A) (X name N))")
+        with self.assertRaisesRegex(ValueError, "Not enough elements in ONCE Conjugation"):
+            run("(once)")
+        with self.assertRaisesRegex(ValueError, "Not enough elements in CASE Conjugation"):
+            run("(case 1)")
+        with self.assertRaisesRegex(ValueError, "Too many elements in COUNT Conjugation"):
+            list(e.evaluate_rule(self.db, [e.CONJ_COUNT, (e.VARIABLE, 'N'), [e.CONJ_AND], [e.CONJ_AND]]))
+        with self.assertRaisesRegex(ValueError, "Not enough elements in AGGREGATE Conjugation"):
+            list(e.evaluate_rule(self.db, [e.CONJ_AGG, "sum", (e.VARIABLE, 'A')]))
+
+    def test_query_traced(self):
+        steps = []
+        results = e.query_traced(self.db, e.body("(X age A) (> A 57)")[0],
+                                 lambda rule, binds: steps.append(rule[0]))
+        self.assertEqual(results, [{ 'X': "pamam_cool@gmail.com", 'A': 58 },
+                                   { 'X': "mammam_cool@gmail.com", 'A': 59 }])
+        self.assertEqual(steps, [e.CONJ_AND, e.PREDICATE] + [e.CONJ_COMP] * 4)
+
+        # Queries run from inside a hook don't disturb the outer trace.
+        nested = []
+        def step(rule, binds):
+            nested.append(rule[0])
+            e.query_with_budget(self.db, e.body("(X father F)")[0], 1)
+        self.assertEqual(e.query_traced(self.db, e.body("(X age A) (> A 57)")[0], step), results)
+        self.assertEqual(nested, steps)
+        self.assertEqual(list(e.evaluate_rule(self.db, e.body("(X age A) (> A 57)")[0])), results)
+
+    def test_difference_and_intersection(self):
+        no_age = list(e.evaluate_rule(self.db, e.body("(- (X father F) (X age A))")[0]))
+        self.assertEqual([b['X'] for b in no_age],
+                         ["cool@gmail.com", "stop@gmail.com", "pa_cool@gmail.com", "mam_cool@gmail.com"])
+
+        with_age = list(e.evaluate_rule(self.db, e.body("(^ (X father F) (X age A))")[0]))
+        self.assertEqual(with_age, [{ 'X': "papa_cool@gmail.com", 'F': "blarg_cool" }])
+
+        # A ground second branch is only checked, never added as a fact.
+        aged = list(e.query(self.db, "(X age A)"))
+        self.assertEqual(list(e.query(self.db, '(- (X age A) ("cool@gmail.com" height 3))')), aged)
+        self.assertEqual(list(e.query(self.db, '(^ (X age A) ("cool@gmail.com" height 3))')), [])
+        self.assertEqual(e.find_violation(self.db, e.body("(X age A)")[0], e.body('("cool@gmail.com" height 3)')[0]),
+                         next(e.evaluate_rule(self.db, e.body("(X age A)")[0])))
+        self.assertIsNone(self.db.get_value("cool@gmail.com", "height"))
+
+    def test_regex_capture(self):
+        query = e.body('("2023-01-02" regex_capture "([0-9]+)-([0-9]+)-([0-9]+)" Parts)')[0]
+        self.assertEqual(list(e.evaluate_rule(self.db, query)),
+                         [{ 'Parts': [(e.LITERAL, "2023"), (e.LITERAL, "01"), (e.LITERAL, "02")] }])
+
+        query = e.body('("2023-01" regex_capture "([0-9]+)-([0-9]+)(-[0-9]+)?" Parts)')[0]
+        self.assertEqual(list(e.evaluate_rule(self.db, query)),
+                         [{ 'Parts': [(e.LITERAL, "2023"), (e.LITERAL, "01"), (e.LITERAL, "")] }])
+
+        query = e.body('("no date here" regex_capture "([0-9]+)-([0-9]+)" Parts)')[0]
+        self.assertEqual(list(e.evaluate_rule(self.db, query)), [])
+
+    def test_rule_passed_as_argument(self):
+        self.db.add_rule("closure", ["R", "X", "Y"], new_rule={
+            "lang": 0,
+            "text": "(| (X R Y) (& (X R Z) (R closure Z Y)))",
+            "body": e.body("(| (X R Y) (& (X R Z) (R closure Z Y)))")[0]
+        })
+        self.assertEqual(list(e.evaluate_rule(self.db, e.body('("father" closure "cool@gmail.com" Y)')[0])),
+                         [{ 'Y': "pa_cool@gmail.com" }, { 'Y': "papa_cool@gmail.com" }, { 'Y': "blarg_cool" }])
+        self.assertEqual(list(e.evaluate_rule(self.db, e.body('("grandfather" closure "cool@gmail.com" Y)')[0])),
+                         [{ 'Y': "papa_cool@gmail.com" }])
+        self.assertEqual(list(e.evaluate_rule(self.db, e.body('("nothing" closure "cool@gmail.com" Y)')[0])), [])
+
+    def test_mixed_number_comparisons(self):
+        self.assertTrue(self.holds("(< 2 2.5)"))
+        self.assertTrue(self.holds("(> 2.5 2)"))
+        self.assertTrue(self.holds("(<= 3 3.0)"))
+        self.assertTrue(self.holds("(>= 3 3.0)"))
+        self.assertTrue(self.holds("(<= 3.0 3)"))
+        self.assertTrue(self.holds("(>= 3.0 3)"))
+        self.assertFalse(self.holds("(< 3 3.0)"))
+        self.assertFalse(self.holds("(> 3.0 3)"))
+        self.assertFalse(self.holds("(>= 2 2.5)"))
+        self.assertFalse(self.holds("(<= 2.5 2)"))
+
+    def test_not_equal_comparison(self):
+        self.assertTrue(self.holds("(!= 2 3)"))
+        self.assertFalse(self.holds("(!= 3 3)"))
+        self.assertTrue(self.holds("(!= 2.5 2)"))
+        self.assertFalse(self.holds("(!= 3 3.0)"))
+        self.assertTrue(self.holds('(!= "foo" "bar")'))
+        self.assertFalse(self.holds('(!= "foo" "foo")'))
+        self.assertTrue(self.holds('(!= "3" 3)'))
+        self.assertEqual(list(e.query(self.db, "(X age A) (!= A 56) (X age 59)")),
+                         [{ 'X': "mammam_cool@gmail.com", 'A': 59 }])
+
+    def test_booleans(self):
+        self.assertTrue(self.holds("(= true true)"))
+        self.assertFalse(self.holds("(= true false)"))
+        self.assertFalse(self.holds("(= true 1)"))
+        self.assertFalse(self.holds("(= false 0)"))
+        self.assertFalse(self.holds('(= true "true")'))
+        self.assertTrue(self.holds("(< false true)"))
+        self.assertFalse(self.holds("(< true false)"))
+        self.assertTrue(self.holds("(!= true 1)"))
+        self.assertFalse(self.holds("(< false 1)"))
+        self.assertFalse(self.holds("(>= true 0)"))
+
+        self.db.add(("cool@gmail.com", "retired", False))
+        self.db.add(("papa_cool@gmail.com", "retired", True))
+        self.assertEqual(self.db.value_type(True), 4)
+        self.assertEqual(list(e.query(self.db, "(cool@gmail.com retired R)")), [{ 'R': False }])
+        self.assertEqual(list(e.query(self.db, "(X retired true)")), [{ 'X': "papa_cool@gmail.com" }])
+        self.assertEqual(list(e.query(self.db, "(= R false) (X retired R)")),
+                         [{ 'R': False, 'X': "cool@gmail.com" }])
+        self.assertEqual(list(e.query(self.db, "(X retired 1)")), [])
+        self.assertEqual(sorted([True, 2, False, "a"], key=e.sort_key), [False, True, 2, "a"])
+
+    def test_list_comparisons(self):
+        self.assertTrue(self.holds("(= A [1 2]) (= B [1 2 3]) (< A B)"))
+        self.assertFalse(self.holds("(= A [1 2 3]) (= B [1 2]) (< A B)"))
+        self.assertTrue(self.holds("(= A [1 [2 5]]) (= B [1 [3]]) (< A B)"))
+        self.assertTrue(self.holds("(= A [1 2.0]) (= B [1.0 2]) (<= A B) (>= A B)"))
+        self.assertFalse(self.holds("(= A [1 2.0]) (= B [1.0 2]) (!= A B)"))
+        self.assertTrue(self.holds("(X listy L) (= M [1 2 3]) (<= L M) (>= L M)"))
+        self.assertFalse(self.holds("(= A [1 2]) (< A 3)"))
+        self.assertFalse(self.holds("(= A [1 2]) (> A 3)"))
+        self.assertTrue(self.holds("(= A [3]) (!= A 3)"))
+
+    def test_once(self):
+        self.assertEqual(list(e.evaluate_rule(self.db, e.body("(once (X father F))")[0])),
+                         [{ 'X': "cool@gmail.com", 'F': "pa_cool@gmail.com" }])
+        self.assertEqual(list(e.evaluate_rule(self.db, e.body("(once (X age A) (> A 55)) (X name N)")[0])),
+                         [{ 'X': "papa_cool@gmail.com", 'A': 56, 'N': "John Cool" }])
+        self.assertEqual(list(e.evaluate_rule(self.db, e.body("(once (X age A) (> A 60))")[0])), [])
+
+    def test_truncate_list(self):
+        self.assertEqual(e.truncate_list([1, 2, 3], 5), [1, 2, 3])
+        self.assertEqual(e.truncate_list([1, 2, 3], 3), [1, 2, 3])
+        self.assertEqual(e.truncate_list(list(range(12)), 4), [0, 1, 2, 3, "... (8 more)"])
+        self.assertEqual(e.truncate_list(list(range(12)), -1), list(range(12)))
+
+    def test_list_to_set(self):
+        query = e.body("([1 2 1 [3 4] 2 [3 4] [4 3]] list_to_set S)")[0]
+        self.assertEqual(list(e.evaluate_rule(self.db, query)), [{ 'S': [
+            (e.LITERAL, 1),
+            (e.LITERAL, 2),
+            (e.LIST, [(e.LITERAL, 3), (e.LITERAL, 4)]),
+            (e.LIST, [(e.LITERAL, 4), (e.LITERAL, 3)])
+        ]}])
+
+        query = e.body("(= L [5 5 5]) (L list_to_set S)")[0]
+        self.assertEqual(list(e.evaluate_rule(self.db, query))[0]['S'], [(e.LITERAL, 5)])
+
+        query = e.body("([1 true 0 false 1.0 [1] [true] [true]] list_to_set S)")[0]
+        self.assertEqual(list(e.evaluate_rule(self.db, query))[0]['S'], [
+            (e.LITERAL, 1), (e.LITERAL, True), (e.LITERAL, 0), (e.LITERAL, False),
+            (e.LIST, [(e.LITERAL, 1)]), (e.LIST, [(e.LITERAL, True)])
+        ])
+
+        with self.assertRaises(ValueError):
+            list(e.evaluate_rule(self.db, e.body("(5 list_to_set S)")[0]))
+
+    def test_member(self):
+        self.assertEqual(list(e.evaluate_rule(self.db, e.body("(X member [1 2 3])")[0])),
+                         [{ 'X': 1 }, { 'X': 2 }, { 'X': 3 }])
+        self.assertEqual(list(e.evaluate_rule(self.db, e.body("(2 member [1 2 3])")[0])), [{}])
+        self.assertEqual(list(e.evaluate_rule(self.db, e.body("(4 member [1 2 3])")[0])), [])
+        self.assertEqual(list(e.evaluate_rule(self.db, e.body("(X member [])")[0])), [])
+        self.assertEqual(list(e.evaluate_rule(self.db, e.body("([A 2] member [1 [1 2] [3 4]])")[0])), [{ 'A': 1 }])
+        self.assertEqual(list(e.evaluate_rule(self.db, e.body("(Y listy L) (X member L)")[0]))[2]['X'], 3)
+        with self.assertRaises(ValueError):
+            list(e.evaluate_rule(self.db, e.body("(X member L)")[0]))
+
+        self.assertIsNone(e.unify([(e.LIST, [(e.VARIABLE, 'A')])], [(e.LITERAL, 1)], {}))
+        self.assertEqual(e.unify([(e.LIST, [(e.VARIABLE, 'A')])], [(e.LITERAL, [(e.LITERAL, 1)])], {}), { 'A': 1 })
+
+    def test_length(self):
+        self.db.remove_rule("length")
+        for (lst, size) in [("[]", 0), ("[5]", 1), ("[1 [2 3] 4]", 3), ("[" + " ".join(["1"] * 2000) + "]", 2000)]:
+            self.assertEqual(list(e.evaluate_rule(self.db, e.body("(" + lst + " length N)")[0])), [{ 'N': size }])
+        self.assertEqual(list(e.evaluate_rule(self.db, e.body("([1 2] length 2)")[0])), [{}])
+        self.assertEqual(list(e.evaluate_rule(self.db, e.body("([1 2] length 3)")[0])), [])
+        self.assertEqual(list(e.evaluate_rule(self.db, e.body("(L length 2)")[0])), [])
+
+    def test_rule_and_facts_with_the_same_name(self):
+        derived = list(e.query(self.db, "(X grandfather G)"))
+        self.db.add(("x@gmail.com", "grandfather", "y@gmail.com"))
+        self.assertEqual(list(e.query(self.db, "(X grandfather G)")),
+                         derived + [{ 'X': "x@gmail.com", 'G': "y@gmail.com" }])
+        self.assertEqual(list(e.query(self.db, '("x@gmail.com" grandfather G)')), [{ 'G': "y@gmail.com" }])
+        self.assertEqual(list(e.query(self.db, '("x@gmail.com" grandfather "y@gmail.com")')), [{}])
+        self.assertEqual(list(e.query(self.db, '("cool@gmail.com" grandfather "y@gmail.com")')), [])
+        self.assertIsNone(self.db.get_value("cool@gmail.com", "grandfather"))
+
+    def test_rules_shadow_built_ins(self):
+        accesses = []
+        results = e.query_audited(self.db, e.body("([1 2 3] length N)")[0],
+                                  lambda name, kind: accesses.append((name, kind)))
+        self.assertEqual(results, [{ 'N': 3 }])
+        self.assertIn(("length", "rule"), accesses)
+
+        self.db.add_rule_text("length", ["List", "Len"], "(= Len 999)")
+        self.assertEqual(list(e.query(self.db, "([1 2 3] length N)")), [{ 'N': 999 }])
+        self.db.add_rule_text("reverse", ["A", "B", "C"], "(= C {A - B})")
+        self.assertEqual(list(e.query(self.db, "(1 reverse 2 C)")), [{ 'C': -1 }])
+
+    def test_list_operations(self):
+        query = e.body("([1 [2 3] 4] reverse R)")[0]
+        self.assertEqual(list(e.evaluate_rule(self.db, query)), [{ 'R': [
+            (e.LITERAL, 4),
+            (e.LIST, [(e.LITERAL, 2), (e.LITERAL, 3)]),
+            (e.LITERAL, 1)
+        ]}])
+
+        query = e.body("([1 [2 [3 [4 [5]]]] [] 6] flatten F)")[0]
+        self.assertEqual(list(e.evaluate_rule(self.db, query))[0]['F'],
+                         [(e.LITERAL, i) for i in range(1, 7)])
+
+        query = e.body("([1 2 3.5] sum_list S)")[0]
+        self.assertEqual(list(e.evaluate_rule(self.db, query)), [{ 'S': 6.5 }])
+        self.assertEqual(list(e.evaluate_rule(self.db, e.body("([1 2] sum_list 4)")[0])), [])
+        with self.assertRaises(ValueError):
+            list(e.evaluate_rule(self.db, e.body('([1 "two"] sum_list S)')[0]))
+
+    def test_cartesian_product(self):
+        fathers = list(e.evaluate_rule(self.db, e.body("(X father F)")[0]))
+        ages = list(e.evaluate_rule(self.db, e.body("(Y age A)")[0]))
+        pairs = list(e.evaluate_rule(self.db, e.body("(X father F) (Y age A)")[0]))
+        self.assertEqual(len(pairs), len(fathers) * len(ages))
+        self.assertSolutionsEqual(pairs, [{ **f, **a } for f in fathers for a in ages], ['X', 'F', 'Y', 'A'])
+
+    def test_relation_metadata(self):
+        self.assertEqual(list(e.evaluate_rule(self.db, e.body("(age relation_arity N)")[0])), [{ 'N': 2 }])
+        self.assertEqual(list(e.evaluate_rule(self.db, e.body("(grandfather relation_arity N)")[0])),
+                         [{ 'N': len(self.db.rules["grandfather"]["args"]) }])
+        self.assertEqual(list(e.evaluate_rule(self.db, e.body("(age relation_size C)")[0])), [{ 'C': 4 }])
+        self.assertEqual(list(e.evaluate_rule(self.db, e.body("(grandfather relation_size C)")[0])), [])
+
+        sizes = list(e.evaluate_rule(self.db, e.body("(R relation_size C)")[0]))
+        self.assertEqual([res['R'] for res in sizes], self.db.attributes)
+        self.assertIn({ 'R': "age", 'C': 4 }, sizes)
+        arities = list(e.evaluate_rule(self.db, e.body("(R relation_arity N)")[0]))
+        self.assertEqual(len(arities), len(self.db.attributes) + len(self.db.rules))
+
+    def test_query_sample(self):
+        query = e.body("(X age A)")[0]
+        everything = list(e.evaluate_rule(self.db, query))
+        sample = e.query_sample(self.db, query, 2, seed=7)
+        self.assertEqual(len(sample), 2)
+        self.assertTrue(all(res in everything for res in sample))
+        self.assertEqual(e.query_sample(self.db, query, 2, seed=7), sample)
+        self.assertEqual(len(e.query_sample(self.db, query, 10)), len(everything))
+
+        counts = { res['X']: 0 for res in everything }
+        for seed in range(2000):
+            for res in e.query_sample(self.db, query, 1, seed=seed):
+                counts[res['X']] += 1
+        for count in counts.values():
+            self.assertAlmostEqual(count / 2000, 1 / len(everything), delta=0.05)
+
+    def test_query_audited(self):
+        accesses = []
+        query = e.body("(X grandfather G) (G R V)")[0]
+        e.query_audited(self.db, query, lambda name, kind: accesses.append((name, kind)))
+        self.assertEqual(accesses[:3], [("grandfather", "rule"), ("father", "facts"), ("father", "facts")])
+        self.assertIn((None, "facts"), accesses)
+
+    def test_query(self):
+        self.assertEqual(list(e.query(self.db, "(X father F) (F age A)")),
+                         list(e.evaluate_rule(self.db, e.body("(X father F) (F age A)")[0])))
+        self.assertEqual(list(e.query(self.db, "(= X Y) (= Y 1)")), [{ 'X': 1, 'Y': 1 }])
+        self.assertEqual(list(e.query(self.db, "(= X Y) (= Y Z) (= X 1)")), [{ 'X': 1, 'Y': 1, 'Z': 1 }])
+        self.assertEqual(list(e.query(self.db, "(= X Y) (= Y 1) (= X 2)")), [])
+        self.assertEqual(list(e.query(self.db, "(= X Y) (= Y X) (= X Z) (= Z 3)")), [{ 'X': 3, 'Y': 3, 'Z': 3 }])
+        self.assertEqual(list(e.query(self.db, "(= X Y) (Y age 59) (X name N)")),
+                         [{ 'X': "mammam_cool@gmail.com", 'Y': "mammam_cool@gmail.com", 'N': "Julie Cool" }])
+        self.assertEqual(list(e.query(self.db, "(= Y 1) (= X [Y])")),
+                         [{ 'Y': 1, 'X': [(e.LITERAL, 1)] }])
+        self.assertEqual(list(e.query(self.db, "(= X Y) (= Z 1)")), [{ 'Z': 1 }])
+        self.assertEqual(list(e.query(self.db, "(= X Y) (= Z 1)", unbound="none")),
+                         [{ 'X': None, 'Y': None, 'Z': 1 }])
+        with self.assertRaises(ValueError):
+            list(e.query(self.db, "(= X Y) (= Z 1)", unbound="error"))
+        self.assertEqual(list(e.query(self.db, "(X age A)", { 'A': 59 })), [{ 'A': 59, 'X': "mammam_cool@gmail.com" }])
+
+    def test_arithmetic(self):
+        self.assertEqual(list(e.query(self.db, "(= X {2 + 3 * 4})")), [{ 'X': 14 }])
+        self.assertEqual(list(e.query(self.db, "(= Y 4) (= X {Y / 2})")), [{ 'Y': 4, 'X': 2.0 }])
+        self.assertEqual(list(e.query(self.db, "(= Y 0) (= X {1 / Y})")), [])
+        self.assertEqual(list(e.query(self.db, "(| (= Y 0) (= Y 2)) (= X {1 / Y})")), [{ 'Y': 2, 'X': 0.5 }])
+        with self.assertRaisesRegex(ValueError, "Undefined variable Z"):
+            list(e.query(self.db, "(= X {1 / Z})"))
+
+    def test_query_distinct(self):
+        query = e.body("(X grandfather G)")[0]
+        self.assertEqual(len(list(e.evaluate_rule(self.db, query))), 3)
+        self.assertEqual(list(e.query_distinct(self.db, query, ['G'])),
+                         [{ 'G': "papa_cool@gmail.com" }, { 'G': "blarg_cool" }])
+        self.assertEqual(len(list(e.query_distinct(self.db, query, ['X', 'G']))), 3)
+
+        query = e.body("(= X 1) (= Y Z)")[0]
+        self.assertEqual(list(e.query_distinct(self.db, query, ['X', 'Y'])), [{ 'X': 1, 'Y': None }])
+        self.assertEqual(list(e.query_distinct(self.db, query, ['X', 'Y'], unbound="skip")), [{ 'X': 1 }])
+        with self.assertRaises(ValueError):
+            list(e.query_distinct(self.db, query, ['X', 'Y'], unbound="error"))
+
+    def test_query_with_budget(self):
+        self.assertEqual(e.query_with_budget(self.db, e.body("(X grandfather G)")[0], 100),
+                         (list(e.evaluate_rule(self.db, e.body("(X grandfather G)")[0])), True))
+
+        self.db.add_rule_text("forever", ["X", "Y"], "(X forever Y)")
+        self.assertEqual(e.query_with_budget(self.db, e.body("(1 forever Y)")[0], 50), ([], False))
+        solutions, finished = e.query_with_budget(self.db, e.body("(| (X age A) (X forever Y))")[0], 50)
+        self.assertEqual(len(solutions), 4)
+        self.assertFalse(finished)
+
+    def test_sort_key(self):
+        values = [[(e.LITERAL, 1), (e.LITERAL, "a")], "b", 2.5, [(e.LITERAL, 1)], 1, "a",
+                  [[e.LITERAL, 1], [e.LITERAL, 2]], 3]
+        self.assertEqual(sorted(values, key=e.sort_key), [
+            1, 2.5, 3, "a", "b",
+            [(e.LITERAL, 1)], [[e.LITERAL, 1], [e.LITERAL, 2]], [(e.LITERAL, 1), (e.LITERAL, "a")]
+        ])
+
+        self.db.add(("cool@gmail.com", "nickname", "joe"))
+        self.db.add(("stop@gmail.com", "nickname", 7))
+        query = e.body('(nickname ordered 1 "asc" [X N])')[0]
+        self.assertEqual([res['N'] for res in e.evaluate_rule(self.db, query)], [7, "joe"])
+
+    def test_count(self):
+        self.assertEqual(list(e.query(self.db, "(count N (X age A))")), [{ 'N': 4 }])
+        self.assertEqual(list(e.query(self.db, "(count N (X age 56))")), [{ 'N': 1 }])
+        self.assertEqual(list(e.query(self.db, "(count N (X age 20))")), [{ 'N': 0 }])
+        self.assertEqual(list(e.query(self.db, "(count 4 (X age A))")), [{}])
+        self.assertEqual(list(e.query(self.db, "(count 3 (X age A))")), [])
+        self.assertEqual(list(e.query(self.db, "(count N (X grandfather G))")), [{ 'N': 3 }])
+
+        # Outer bindings reach the goals, and each outer solution gets its own count.
+        self.assertEqual(list(e.query(self.db, "(| (= G papa_cool@gmail.com) (= G blarg_cool)) (count N (X grandfather G))")),
+                         [{ 'G': "papa_cool@gmail.com", 'N': 2 }, { 'G': "blarg_cool", 'N': 1 }])
+
+        # Counting a missing fact doesn't create it.
+        self.assertEqual(list(e.query(self.db, '(count N ("cool@gmail.com" height 3))')), [{ 'N': 0 }])
+        self.assertIsNone(self.db.get_value("cool@gmail.com", "height"))
+
+    def test_aggregates(self):
+        self.assertEqual(list(e.query(self.db, "(sum A S (X age A))")), [{ 'S': 53 + 56 + 58 + 59 }])
+        self.assertEqual(list(e.query(self.db, "(max A M (X age A))")), [{ 'M': 59 }])
+        self.assertEqual(list(e.query(self.db, "(min A M (X age A))")), [{ 'M': 53 }])
+        self.assertEqual(list(e.query(self.db, "(max A M (X age A)) (X age M)")),
+                         [{ 'M': 59, 'X': "mammam_cool@gmail.com" }])
+
+        self.assertEqual(list(e.query(self.db, "(sum A S (X age 20) (X age A))")), [{ 'S': 0 }])
+        self.assertEqual(list(e.query(self.db, "(max A M (X age 20) (X age A))")), [])
+        self.assertEqual(list(e.query(self.db, "(sum F S (X father F))")), [])
+        self.assertEqual(list(e.query(self.db, "(sum A S (X father F))")), [])
+
+        self.assertEqual(list(e.query(self.db, '(sum 3 S ("cool@gmail.com" height 3))')), [{ 'S': 0 }])
+        self.assertEqual(list(e.query(self.db, '(max 3 M ("cool@gmail.com" height 3))')), [])
+        self.assertIsNone(self.db.get_value("cool@gmail.com", "height"))
+
+    def test_collect(self):
+        for n in range(1, 7):
+            self.db.add(("n" + str(n), "num", n))
+        # Only the even numbers, each doubled.
+        self.assertEqual(list(e.query(self.db, "(collect D L (X num N) (= 0 {N % 2}) (= D {N * 2}))")),
+                         [{ 'L': [(e.LITERAL, 4), (e.LITERAL, 8), (e.LITERAL, 12)] }])
+        self.assertEqual(list(e.query(self.db, "(collect [X N] L (X num N) (> N 4))")),
+                         [{ 'L': [(e.LIST, [(e.LITERAL, "n5"), (e.LITERAL, 5)]),
+                                  (e.LIST, [(e.LITERAL, "n6"), (e.LITERAL, 6)])] }])
+        self.assertEqual(list(e.query(self.db, "(collect N L (X num N) (> N 6))")), [{ 'L': [] }])
+        self.assertEqual(list(e.query(self.db, "(collect M L (X num N))")), [])
+
+        # Outer bindings reach the goals, and nothing bound inside leaks out.
+        self.assertEqual(list(e.query(self.db, "(= Min 5) (collect N L (X num N) (>= N Min))")),
+                         [{ 'Min': 5, 'L': [(e.LITERAL, 5), (e.LITERAL, 6)] }])
+        self.assertEqual(list(e.query(self.db, "(collect N [A B] (X num N) (> N 4))")), [{ 'A': 5, 'B': 6 }])
+
+    def test_negated_aggregates(self):
+        # Fathers of only one child: F comes from the outer goal, and N
+        # stays inside the difference.
+        self.assertEqual(list(e.query(self.db, "(- (X father F) (& (count N (C father F)) (> N 1)))")), [
+            { 'X': "pa_cool@gmail.com", 'F': "papa_cool@gmail.com" },
+            { 'X': "mam_cool@gmail.com", 'F': "pamam_cool@gmail.com" },
+            { 'X': "papa_cool@gmail.com", 'F': "blarg_cool" }
+        ])
+        self.assertEqual(list(e.query(self.db, "(^ (X father F) (& (count N (C father F)) (> N 1)))")), [
+            { 'X': "cool@gmail.com", 'F': "pa_cool@gmail.com" },
+            { 'X': "stop@gmail.com", 'F': "pa_cool@gmail.com" }
+        ])
+
+        # Fathers none of whose children are over 55, through a rule.
+        self.db.add_rule_text("oldest_child", ["F", "M"], "(max A M (C father F) (C age A))")
+        self.assertEqual([res['F'] for res in e.query(self.db, "(- (X father F) (& (F oldest_child M) (> M 55)))")],
+                         ["pa_cool@gmail.com", "pa_cool@gmail.com", "papa_cool@gmail.com", "pamam_cool@gmail.com"])
+        self.db.check_stratification()
+
+    def test_ordered(self):
+        query = e.body('(age ordered 1 "desc" [X A])')[0]
+        self.assertEqual([res['A'] for res in e.evaluate_rule(self.db, query)], [59, 58, 56, 53])
+        query = e.body('(age ordered 1 "asc" [X A])')[0]
+        self.assertEqual([res['A'] for res in e.evaluate_rule(self.db, query)], [53, 56, 58, 59])
+        query = e.body('(age ordered 0 "asc" [X A])')[0]
+        self.assertEqual([res['X'] for res in e.evaluate_rule(self.db, query)], [
+            "mammam_cool@gmail.com", "mampa_cool@gmail.com", "pamam_cool@gmail.com", "papa_cool@gmail.com"
+        ])
+
+        with self.assertRaises(ValueError):
+            list(e.evaluate_rule(self.db, e.body('(age ordered 2 "asc" [X A])')[0]))
+
+    def test_alternative_bindings_reach_later_goals(self):
+        query = e.body("(= Z 1) (| (= Y 56) (& (= W 2) (= Y 53))) (X age Y)")[0]
+        self.assertEqual(list(e.evaluate_rule(self.db, query)), [
+            { 'Z': 1, 'Y': 56, 'X': "papa_cool@gmail.com" },
+            { 'Z': 1, 'W': 2, 'Y': 53, 'X': "mampa_cool@gmail.com" }
+        ])
+
+    def test_refine_query(self):
+        fathers = e.evaluate_rule(self.db, e.body("(X father F)")[0])
+        self.assertEqual(next(fathers), { 'X': "cool@gmail.com", 'F': "pa_cool@gmail.com" })
+
+        refined = e.refine_query(self.db, fathers, e.body("(F age A)")[0])
+        self.assertEqual(list(refined), [
+            { 'X': "pa_cool@gmail.com", 'F': "papa_cool@gmail.com", 'A': 56 },
+            { 'X': "mam_cool@gmail.com", 'F': "pamam_cool@gmail.com", 'A': 58 }
+        ])
+        self.assertIsNone(next(fathers, None))
+
+    def test_elementwise_compare(self):
+        self.assertTrue(self.holds('([1 2 3] elementwise_compare [2 3 4] "<")'))
+        self.assertTrue(self.holds('([1 2 3] elementwise_compare [1 2.5 3] "<=")'))
+        self.assertFalse(self.holds('([1 5 3] elementwise_compare [2 3 4] "<")'))
+        self.assertFalse(self.holds('([1 2] elementwise_compare [2 3 4] "<")'))
+        self.assertFalse(self.holds('([1 2] elementwise_compare [2 "a"] "<")'))
+
+    def test_find_violation(self):
+        self.assertEqual(e.find_violation(self.db, e.body("(X father F)")[0], e.body("(F age A)")[0]),
+                         { 'X': "cool@gmail.com", 'F': "pa_cool@gmail.com" })
+        self.assertIsNone(e.find_violation(self.db, e.body("(X age A)")[0], e.body("(>= A 18)")[0]))
+        self.assertEqual(e.find_violation(self.db, e.body("(X father F)")[0], e.body("(F name N)")[0]),
+                         { 'X': "papa_cool@gmail.com", 'F': "blarg_cool" })
+
+    def test_value_conversions(self):
+        def solve(query):
+            return list(e.evaluate_rule(self.db, e.body(query)[0]))
+
+        self.assertEqual(solve('("42" to_number N)'), [{ 'N': 42 }])
+        self.assertEqual(solve('("-2.5" to_number N)'), [{ 'N': -2.5 }])
+        self.assertEqual(solve('("4x2" to_number N)'), [])
+        self.assertEqual(solve('("1e3" to_number N)'), [{ 'N': 1000.0 }])
+        self.assertEqual(solve('(".5" to_number N)'), [{ 'N': 0.5 }])
+        for text in ["nan", "inf", "-Infinity", "1_000", " 42", "42 ", "", "-", "."]:
+            self.assertEqual(solve('("' + text + '" to_number N)'), [], text)
+        with self.assertRaises(ValueError):
+            solve('(T to_number true)')
+        self.assertEqual(solve('(T to_number 42)'), [{ 'T': "42" }])
+        self.assertEqual(solve('("42" to_number 42)'), [{}])
+
+        self.assertEqual(solve('(42 to_text T)'), [{ 'T': "42" }])
+        self.assertEqual(solve('([1 "b" [2.5]] to_text T)'), [{ 'T': "[1 b [2.5]]" }])
+        self.assertEqual(solve('(X age 56) (X to_text T)'), [{ 'X': "papa_cool@gmail.com", 'T': "papa_cool@gmail.com" }])
+        with self.assertRaises(ValueError):
+            solve('(X to_text T)')
+
+    def test_query_best_effort(self):
+        query = e.body('(X age A) (> A 57) (X name "Nobody")')[0]
+        self.assertEqual(list(e.evaluate_rule(self.db, query)), [])
+
+        ranked = e.query_best_effort(self.db, query)
+        self.assertEqual(ranked[:2], [({ 'X': "pamam_cool@gmail.com", 'A': 58 }, 2),
+                                      ({ 'X': "mammam_cool@gmail.com", 'A': 59 }, 2)])
+        self.assertEqual([satisfied for (_, satisfied) in ranked[2:]], [1, 1])
+
+        self.assertEqual(e.query_best_effort(self.db, e.body("(X age 56)")[0]),
+                         [({ 'X': "papa_cool@gmail.com" }, 1)])
+
+        # An undefined variable in a later goal leaves only that goal unsatisfied.
+        ranked = e.query_best_effort(self.db, e.body("(X age A) (> B 1)")[0])
+        self.assertEqual(len(ranked), len(list(e.evaluate_rule(self.db, e.body("(X age A)")[0]))))
+        self.assertEqual({ satisfied for (_, satisfied) in ranked }, { 1 })
+
+        self.db.add_rule_text("older", ["X", "Y"], "(X age A) (Y age B) (> A B)")
+        with self.assertRaises(ValueError):
+            e.query_best_effort(self.db, e.body("(X age A) (older X)")[0])
+
+    def test_query_into_attribute(self):
+        added = e.query_into_attribute(self.db, e.body("(X father F) (F father G)")[0], "paternal_grandfather", "X", "G")
+        self.assertEqual(added, 3)
+        self.assertEqual(list(e.evaluate_rule(self.db, e.body("(X paternal_grandfather G)")[0])), [
+            { 'X': "cool@gmail.com", 'G': "papa_cool@gmail.com" },
+            { 'X': "stop@gmail.com", 'G': "papa_cool@gmail.com" },
+            { 'X': "pa_cool@gmail.com", 'G': "blarg_cool" }
+        ])
+
+        with self.assertRaises(ValueError):
+            e.query_into_attribute(self.db, e.body("(X father F)")[0], "nothing", "X", "Unbound")
+        self.assertFalse("nothing" in self.db.attributes)
+
+    def test_case(self):
+        query = e.body('(X age A) (case A (56 (= R "fifty-six")) (58 (= R "fifty-eight") (X name N)) (else (= R "other")))')[0]
+        self.assertEqual(list(e.evaluate_rule(self.db, query)), [
+            { 'X': "papa_cool@gmail.com", 'A': 56, 'R': "fifty-six" },
+            { 'X': "mampa_cool@gmail.com", 'A': 53, 'R': "other" },
+            { 'X': "pamam_cool@gmail.com", 'A': 58, 'R': "fifty-eight", 'N': "Ed Cool" },
+            { 'X': "mammam_cool@gmail.com", 'A': 59, 'R': "other" }
+        ])
+
+        query = e.body('(case "Joe Cool" ("Joe Cool" (= R 1)))')[0]
+        self.assertEqual(list(e.evaluate_rule(self.db, query)), [{ 'R': 1 }])
+        query = e.body('(case 3 (1 (= R 1)) (2 (= R 2)))')[0]
+        self.assertEqual(list(e.evaluate_rule(self.db, query)), [])
+        with self.assertRaises(ValueError):
+            list(e.evaluate_rule(self.db, e.body('(case S (1 (= R 1)))')[0]))
+
+        self.assertEqual(list(e.query(self.db, '(= S true) (case S (1 (= R 1)) (true (= R 2)) (else (= R 3)))')),
+                         [{ 'S': True, 'R': 2 }])
+        self.assertEqual(list(e.query(self.db, '(= S 0) (case S (false (= R 1)) (else (= R 3)))')),
+                         [{ 'S': 0, 'R': 3 }])
+
+    def test_custom_unifier(self):
+        def roughly(query, fact, binds, global_binds):
+            (q_type, q_val), (f_type, f_val) = query[2], fact[2]
+            if q_type == e.LITERAL and isinstance(q_val, int) and isinstance(f_val, int) and abs(q_val - f_val) <= 1:
+                query = query[:2] + [fact[2]]
+            return e.unify(query, fact, binds, global_binds)
+
+        self.db.set_unifier("age", roughly)
+        self.assertEqual(list(e.evaluate_rule(self.db, e.body("(X age 57)")[0])),
+                         [{ 'X': "papa_cool@gmail.com" }, { 'X': "pamam_cool@gmail.com" }])
+        self.assertEqual(list(e.evaluate_rule(self.db, e.body("(X age 54)")[0])),
+                         [{ 'X': "mampa_cool@gmail.com" }])
+        self.assertEqual(list(e.evaluate_rule(self.db, e.body("(X age A)")[0]))[0],
+                         { 'X': "papa_cool@gmail.com", 'A': 56 })
+
+        other = e.load_from_file("../test.db.json")
+        self.assertEqual(list(e.evaluate_rule(other, e.body("(X age 57)")[0])), [])
+        self.db.set_unifier("age", None)
+        self.assertEqual(list(e.evaluate_rule(self.db, e.body("(X age 57)")[0])), [])
+
+    def test_entity_attribute_lookup(self):
+        for i in range(200):
+            self.db.add(("entity" + str(i), "age", 18 + i % 50))
+        attempts = []
+        def counting(query, fact, binds, global_binds):
+            attempts.append(fact)
+            return e.unify(query, fact, binds, global_binds)
+
+        self.db.set_unifier("age", counting)
+        self.assertEqual(list(e.query(self.db, "(papa_cool@gmail.com age A)")), [{ 'A': 56 }])
+        self.assertEqual(len(attempts), 1)
+        self.assertEqual(list(e.query(self.db, "(nobody age A)")), [])
+        self.assertEqual(list(e.query(self.db, "(papa_cool@gmail.com height A)")), [])
+        self.assertEqual(len(attempts), 1)
+
+        self.assertEqual(len(list(e.query(self.db, "(X age 56)"))), 5)
+        self.assertGreater(len(attempts), 200)
+
+        unifications = []
+        def counting_unify(query, fact, binds={}, global_binds={}):
+            unifications.append(fact)
+            return unify(query, fact, binds, global_binds)
+        unify = e.unify
+        e.unify = counting_unify
+        self.addCleanup(setattr, e, "unify", unify)
+        found = list(e.query(self.db, "(papa_cool@gmail.com R V)"))
+        self.assertEqual(len(found), len(list(self.db.get_attributes_values("papa_cool@gmail.com"))))
+        self.assertIn({ 'R': "age", 'V': 56 }, found)
+        self.assertLessEqual(len([f for f in unifications if len(f) == 3]), len(self.db.attributes))
+
+    def test_float_tolerance(self):
+        self.db.add(("papa_cool@gmail.com", "weight", 80.0))
+        self.db.set_unifier("weight", e.float_tolerance(0.01))
+        self.assertEqual(list(e.evaluate_rule(self.db, e.body("(X weight 80.005)")[0])),
+                         [{ 'X': "papa_cool@gmail.com" }])
+        self.assertEqual(list(e.evaluate_rule(self.db, e.body("(X weight 80.02)")[0])), [])
+        self.assertEqual(list(e.query(self.db, '("papa_cool@gmail.com" weight 80.005)')), [{}])
+        self.assertEqual(list(e.query(self.db, '("papa_cool@gmail.com" weight 80.02)')), [])
+        self.assertEqual(self.db.get_value("papa_cool@gmail.com", "weight"), 80.0)
+        self.assertEqual(list(e.evaluate_rule(self.db, e.body("(= W 79.999) (X weight W)")[0])),
+                         [{ 'W': 79.999, 'X': "papa_cool@gmail.com" }])
+
+    def test_database_float_tolerance(self):
+        self.assertFalse(self.holds("(= 80.0 80.0000001)"))
+        self.assertTrue(self.holds("(!= 80.0 80.0000001)"))
+
+        self.db.set_float_tolerance(0.001)
+        self.assertTrue(self.holds("(= 80.0 80.0000001)"))
+        self.assertTrue(self.holds("(= W 80.0) (= W 80.0005)"))
+        self.assertFalse(self.holds("(= 80.0 80.01)"))
+        self.assertFalse(self.holds("(!= 80.0 80.0000001)"))
+        self.assertTrue(self.holds("(!= 80.0 80.01)"))
+        self.assertTrue(self.holds("(= W 80.0) (!= W 80.01)"))
+        self.assertFalse(self.holds("(= true 1)"))
+        self.assertTrue(self.holds("(< 80.0 80.0005)"))
+        # Facts are still matched exactly.
+        self.db.add(("papa_cool@gmail.com", "weight", 80.0))
+        self.assertFalse(self.holds("(X weight 80.0005)"))
+
+    def test_query_require_bound(self):
+        query = e.body("(X age A)")[0]
+        self.assertEqual(e.query_require_bound(self.db, query, ['X', 'A']),
+                         list(e.evaluate_rule(self.db, query)))
+        query = e.body("(X age A) (| (= Y 1) (= Z 2))")[0]
+        self.assertEqual(len(e.query_require_bound(self.db, query, ['X'])), 8)
+        with self.assertRaises(ValueError):
+            e.query_require_bound(self.db, query, ['Y'])
+
+    def test_query_rows(self):
+        query = e.body('(X age A) (X name N)')[0]
+        rows = e.query_rows(self.db, query, [("X", "entity"), ("A", "int")])
+        self.assertEqual(rows[0], ("papa_cool@gmail.com", 56))
+        self.assertEqual(len(rows), len(list(e.evaluate_rule(self.db, query))))
+
+        with self.assertRaises(TypeError):
+            e.query_rows(self.db, query, [("X", "entity"), ("A", "string")])
+        with self.assertRaises(ValueError):
+            e.query_rows(self.db, query, [("Y", "entity")])
+
+        query = e.body('(X age A) (| (= X "papa_cool@gmail.com") (= Y 1))')[0]
+        self.assertEqual(e.query_rows(self.db, query, [("X", "entity"), ("Y", "int")], unbound="skip"),
+                         [(x, 1) for (x, _) in e.query_rows(self.db, e.body("(X age A)")[0],
+                                                             [("X", "entity"), ("A", "int")])])
+        rows = e.query_rows(self.db, query, [("X", "entity"), ("Y", "int")], unbound="none")
+        self.assertEqual(rows[0], ("papa_cool@gmail.com", None))
+        with self.assertRaises(ValueError):
+            e.query_rows(self.db, query, [("X", "entity")], unbound="missing")
+
+    def test_ground_rule(self):
+        grounded = e.ground_rule(self.db, "grandfather")
+        self.assertEqual(len(grounded), 3)
+        self.assertEqual(grounded[0], [e.CONJ_AND,
+            [e.PREDICATE, (e.LITERAL, "cool@gmail.com"), [e.LITERAL, "father"], (e.LITERAL, "pa_cool@gmail.com")],
+            [e.PREDICATE, (e.LITERAL, "pa_cool@gmail.com"), [e.LITERAL, "father"], (e.LITERAL, "papa_cool@gmail.com")]
+        ])
+        self.assertEqual(grounded[2][2][3], (e.LITERAL, "blarg_cool"))
+        self.assertEqual(len(e.ground_rule(self.db, "grandfather", limit=2)), 2)
+
 if __name__ == '__main__':
     unittest.main()
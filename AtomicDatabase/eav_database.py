@@ -10,6 +10,8 @@ import copy
 import inspect
 import pickle
 import json
+import random
+import zlib
 from functools import wraps
 
 import resource, sys
@@ -36,6 +38,13 @@ UNIFY      = 4 + 5
 CONJ_COMP  = 5 + 5
 CONJ_COND  = 6 + 5
 BUILTIN    = 7 + 5
+CONJ_DIFF  = 8 + 5
+CONJ_INTER = 9 + 5
+CONJ_ONCE  = 10 + 5
+CONJ_CASE  = 11 + 5
+CONJ_COUNT = 12 + 5
+CONJ_AGG   = 13 + 5
+CONJ_COLLECT = 14 + 5
 
 
 def ast_value_wrap(val, decend=True):
@@ -48,6 +57,24 @@ def ast_value_wrap(val, decend=True):
     else:
         return (LITERAL, val)
 
+def occurs(name, items, binds={}, global_binds={}, seen=()):
+    # Whether the variable `name` appears in `items`, directly, through a
+    # variable linked to it or inside the list a variable is bound to.
+    for (tpe, val) in items:
+        if tpe == VARIABLE:
+            end, bound = binding_end(val, binds, global_binds)
+            if val == name or end == name:
+                return True
+            if isinstance(bound, list) and not end in seen and occurs(name, bound, binds, global_binds, seen + (end,)):
+                return True
+        elif isinstance(val, list) and occurs(name, val, binds, global_binds, seen):
+            return True
+    return False
+
+def value_or_name(name, binds, global_binds):
+    value = get_binds(name, binds, global_binds)
+    return name if value == None else value
+
 def unify(a, b, binds={}, global_binds={}):
     for i in range(0, min(len(a), len(b))):
         (a_type, a_val) = a[i]
@@ -73,8 +100,10 @@ def unify(a, b, binds={}, global_binds={}):
                 a_val, b_val = b_val, a_val
                 a_type, b_type = b_type, a_type
 
-            a_val = [(get_binds(v, binds, global_binds) or v) if t == VARIABLE else v for t, v in a_val]
-            b_val = [get_binds(v, binds, global_binds) if t == VARIABLE else v for t, v in b_val]
+            # Unbound variables stay as names, so binding a variable to a part
+            # of the list that contains it fails the occurs check.
+            a_val = [value_or_name(v, binds, global_binds) if t == VARIABLE else v for t, v in a_val]
+            b_val = [value_or_name(v, binds, global_binds) if t == VARIABLE else v for t, v in b_val]
             new_binds = destructure(a_val, b_val)
             if new_binds != None:
                 binds_so_far = copy.copy(binds)
@@ -101,12 +130,20 @@ def unify(a, b, binds={}, global_binds={}):
                     return None
                 else:
                     binds = res
+        elif (a_type == LIST and b_type == LITERAL) or (a_type == LITERAL and b_type == LIST):
+            # A list only matches a literal that holds a list itself.
+            if not isinstance(a_val, list) or not isinstance(b_val, list):
+                return None
+            res = unify([(LIST, a_val)], [(LIST, b_val)], copy.copy(binds), global_binds)
+            if res == None:
+                return None
+            binds = res
         elif (b_type == LIST and a_type == VARIABLE) or (a_type == LIST and b_type == VARIABLE):
             if b_type == VARIABLE:
                 a_val, b_val = b_val, a_val
                 a_type, b_type = b_type, a_type
 
-            a_kval = get_binds(a_val, binds, global_binds)
+            a_end, a_kval = binding_end(a_val, binds, global_binds)
             if a_kval != None and isinstance(a_kval, list):
                 new_binds = unify([(b_type, b_val)], [(LIST, a_kval)], copy.copy(binds), global_binds)
                 if new_binds != None:
@@ -114,7 +151,10 @@ def unify(a, b, binds={}, global_binds={}):
                 else:
                     return None
             elif a_kval == None:
-                binds[a_val] = b_val
+                # Binding X to a list that contains X would make it infinite.
+                if occurs(a_end, b_val, binds, global_binds):
+                    return None
+                binds[a_end] = b_val
             else:
                 return None
         elif (a_type == VARIABLE and b_type == LITERAL) or (b_type == VARIABLE and a_type == LITERAL):
@@ -122,28 +162,37 @@ def unify(a, b, binds={}, global_binds={}):
                 a_val, b_val = b_val, a_val
                 a_type, b_type = b_type, a_type
 
-            a_kval = get_binds(a_val, binds, global_binds)
-            if a_kval != None and a_kval == b_val:
+            a_end, a_kval = binding_end(a_val, binds, global_binds)
+            if a_kval != None and same_value(a_kval, b_val):
                 continue
-            elif not a_kval:
-                binds[a_val] = b_val
+            elif a_kval == None:
+                binds[a_end] = b_val
             else:
                 return None
         elif a_type == LITERAL and b_type == LITERAL:
-            if a_val == b_val:
+            if same_value(a_val, b_val):
                 continue
             else:
                 return None
         elif a_type == VARIABLE and b_type == VARIABLE:
-            a_kval = get_binds(a_val, binds, global_binds)
-            b_kval = get_binds(b_val, binds, global_binds)
+            # Values go on the last variable of a chain, so every variable
+            # linked to it sees them.
+            a_end, a_kval = binding_end(a_val, binds, global_binds)
+            b_end, b_kval = binding_end(b_val, binds, global_binds)
 
-            if a_kval != None and b_kval != None and a_kval == b_kval:
+            if a_kval != None and b_kval != None and same_value(a_kval, b_kval):
                 continue
-            elif not a_kval:
-                binds[a_val] = b_kval
-            elif not b_kval:
-                binds[b_val] = a_kval
+            elif a_kval == None and b_kval == None:
+                if a_end != b_end:
+                    binds[a_end] = (VARIABLE, b_end)
+            elif a_kval == None:
+                if isinstance(b_kval, list) and occurs(a_end, b_kval, binds, global_binds):
+                    return None
+                binds[a_end] = b_kval
+            elif b_kval == None:
+                if isinstance(a_kval, list) and occurs(b_end, a_kval, binds, global_binds):
+                    return None
+                binds[b_end] = a_kval
             else:
                 return None
     return binds
@@ -155,33 +204,257 @@ types = [
     { "name": "UNIFY Command", "arg_count": (2, 2) },
     { "name": "COMPARASON Conjugation", "arg_count": (3, 3) },
     { "name": "CONDITION Conjugation", "arg_count": (1, -1) },
+    { "name": "BUILTIN Command", "arg_count": (0, -1) },
+    { "name": "DIFFERENCE Conjugation", "arg_count": (2, 2) },
+    { "name": "INTERSECTION Conjugation", "arg_count": (2, 2) },
+    { "name": "ONCE Conjugation", "arg_count": (1, -1) },
+    { "name": "CASE Conjugation", "arg_count": (2, -1) },
+    { "name": "COUNT Conjugation", "arg_count": (2, 2) },
+    { "name": "AGGREGATE Conjugation", "arg_count": (4, 4) },
+    { "name": "COLLECT Conjugation", "arg_count": (3, 3) },
 ]
 
 def evaluate_exprs(lst, binds):
     res = []
     for e in lst:
         if e[0] == EXPR:
-            res.append((LITERAL, eval_expr(e[1], binds)))
+            values = { k: get_binds(k, binds, {}) for k in binds }
+            values = { k: v for k, v in values.items() if v != None }
+            unbound = [t for t in e[1] if isinstance(t, str) and t[:1].isupper() and not t in values]
+            if unbound:
+                raise ValueError("Undefined variable " + unbound[0] + " in expression!")
+            try:
+                res.append((LITERAL, eval_expr(e[1], values)))
+            except NameError as err:
+                raise ValueError("Undefined name in expression: " + str(err))
         elif e[0] == LIST:
             res.append((LIST, evaluate_exprs(e[1], binds)))
         else:
             res.append(e)
     return res
 
+def special_args(tail, count):
+    if len(tail) - 1 != count:
+        raise ValueError("Wrong number of arguments in " + tail[1][1].upper() +\
+                         " Rule! Expected "+str(count)+", found " + str(len(tail) - 1) + ".")
+    return tail[:1] + tail[2:]
+
+def special_list(arg, tail):
+    (tpe, val) = arg
+    if (tpe == LIST or tpe == LITERAL) and isinstance(val, list):
+        return val
+    raise ValueError(tail[1][1].upper() + " needs a bound list, got " + str(val) + "!")
+
+def special_unify(arg, value, binds):
+    res = unify([arg], [value], copy.copy(binds))
+    return [res] if res != None else []
+
+def regex_capture(tail, binds, db):
+    text, pattern, groups = special_args(tail, 3)
+    if text[0] != LITERAL or pattern[0] != LITERAL:
+        raise ValueError("REGEX_CAPTURE needs both the text and the pattern to be bound!")
+
+    match = re.search(pattern[1], text[1])
+    if not match:
+        return []
+    # Optional groups that didn't take part in the match capture "".
+    return special_unify(groups, (LIST, [(LITERAL, g or "") for g in match.groups()]), binds)
+
+def elementwise_compare(tail, binds, db):
+    a, b, op = special_args(tail, 3)
+    a, b = special_list(a, tail), special_list(b, tail)
+    if op[0] != LITERAL:
+        raise ValueError("ELEMENTWISE_COMPARE needs a bound comparison operator!")
+    if len(a) != len(b):
+        return []
+
+    for ((a_type, a_val), (b_type, b_val)) in zip(a, b):
+        try:
+            if a_type != LITERAL or b_type != LITERAL or not compare(op[1], a_val, b_val):
+                return []
+        except TypeError:
+            return []
+    return None
+
+def value_text(value):
+    if isinstance(value, list):
+        return "[" + " ".join(value_text(v[1]) for v in value) + "]"
+    else:
+        return str(value)
+
+def to_number(tail, binds, db):
+    text, number = special_args(tail, 2)
+    if text[0] == LITERAL:
+        # Only plain decimal numbers: int() and float() would also take "nan",
+        # "inf", "1_000" and surrounding whitespace.
+        if not isinstance(text[1], str) or not re.fullmatch(r"[-+]?(\d+\.?\d*|\.\d+)([eE][-+]?\d+)?", text[1]):
+            return []
+        parsed = int(text[1]) if re.fullmatch(r"[-+]?\d+", text[1]) else float(text[1])
+        return special_unify(number, (LITERAL, parsed), binds)
+    elif number[0] == LITERAL and isinstance(number[1], (int, float)) and not isinstance(number[1], bool):
+        return special_unify(text, (LITERAL, str(number[1])), binds)
+    raise ValueError("TO_NUMBER needs either the text or the number to be bound!")
+
+def to_text(tail, binds, db):
+    value, text = special_args(tail, 2)
+    if value[0] == VARIABLE:
+        raise ValueError("TO_TEXT needs a bound value, " + value[1] + " is unbound!")
+    return special_unify(text, (LITERAL, value_text(value[1])), binds)
+
+def length(tail, binds, db):
+    lst, size = special_args(tail, 2)
+    # Lists of a given length aren't generated, so an unbound list fails.
+    if lst[0] == VARIABLE:
+        return []
+    return special_unify(size, (LITERAL, len(special_list(lst, tail))), binds)
+
+def member(tail, binds, db):
+    element, lst = special_args(tail, 2)
+    results = []
+    for item in special_list(lst, tail):
+        results += special_unify(element, item, binds)
+    return results
+
+def list_to_set(tail, binds, db):
+    lst, result = special_args(tail, 2)
+    deduped = []
+    for v in special_list(lst, tail):
+        if not any(same_value(v[1], d[1]) for d in deduped):
+            deduped.append(v)
+    return special_unify(result, (LIST, deduped), binds)
+
+def reverse(tail, binds, db):
+    lst, result = special_args(tail, 2)
+    return special_unify(result, (LIST, special_list(lst, tail)[::-1]), binds)
+
+def flatten(tail, binds, db):
+    lst, result = special_args(tail, 2)
+    # Walked with an explicit stack so deeply nested lists can't hit the
+    # recursion limit.
+    flat = []
+    stack = [iter(special_list(lst, tail))]
+    while stack:
+        item = next(stack[-1], None)
+        if item is None:
+            stack.pop()
+        elif isinstance(item[1], list):
+            stack.append(iter(item[1]))
+        else:
+            flat.append(item)
+    return special_unify(result, (LIST, flat), binds)
+
+def sum_list(tail, binds, db):
+    lst, result = special_args(tail, 2)
+    total = 0
+    for (tpe, val) in special_list(lst, tail):
+        if tpe != LITERAL or isinstance(val, bool) or not isinstance(val, (int, float)):
+            raise ValueError("SUM_LIST can only add numbers, found " + str(val) + "!")
+        total += val
+    return special_unify(result, (LITERAL, total), binds)
+
+def ordered(tail, binds, db):
+    attribute, position, direction, fact = special_args(tail, 4)
+    if attribute[0] != LITERAL or position[0] != LITERAL or direction[0] != LITERAL:
+        raise ValueError("ORDERED needs the attribute, position and direction to be bound!")
+    if not position[1] in (0, 1) or not direction[1] in ("asc", "desc"):
+        raise ValueError("ORDERED sorts on position 0 (entity) or 1 (value), \"asc\" or \"desc\"!")
+
+    attr = db.resolve_alias(attribute[1])
+    pairs = [(db.entities[e], v) for (e, a, v) in db.eavs.values()
+             if db.attributes[a] == attr and not db.is_negative(db.entities[e], attr, v)]
+    pairs.sort(key=lambda p: sort_key(p[position[1]]), reverse=direction[1] == "desc")
+
+    results = []
+    for (e, v) in pairs:
+        results += special_unify(fact, (LIST, [(LITERAL, e), ast_value_wrap(v, False)]), binds)
+    return results
+
+def relation_names(relation, db, include_rules):
+    if relation[0] == LITERAL:
+        return [db.resolve_alias(relation[1])]
+    return db.attributes + (list(db.rules) if include_rules else [])
+
+def relation_arity(tail, binds, db):
+    relation, arity = special_args(tail, 2)
+    results = []
+    for name in relation_names(relation, db, True):
+        # Attribute facts always relate an entity to a value.
+        if name in db.attributes:
+            n = 2
+        elif name in db.rules:
+            n = len(db.rules[name]["args"] or [])
+        else:
+            continue
+        for res in special_unify(relation, (LITERAL, name), binds):
+            results += special_unify(arity, (LITERAL, n), res)
+    return results
+
+def relation_size(tail, binds, db):
+    relation, size = special_args(tail, 2)
+    results = []
+    for name in relation_names(relation, db, False):
+        if not name in db.attributes:
+            continue
+        count = sum(1 for (e, a, v) in db.get_entities_values(name)
+                    if not db.is_negative(db.entities[e], name, v))
+        for res in special_unify(relation, (LITERAL, name), binds):
+            results += special_unify(size, (LITERAL, count), res)
+    return results
+
+# Special rules get the call (with bound variables already substituted), the
+# current bindings and the database. Returning None means "succeed once, bind nothing",
+# otherwise the rule yields each set of bindings it returns.
 SPECIAL_RULES = {
-    "print": lambda tail: print("\nInternal AD Log: " + str(tail[-1])),
+    "print": lambda tail, binds, db: print("\nInternal AD Log: " + str(tail[-1])),
+    "regex_capture": regex_capture,
+    "list_to_set": list_to_set,
+    "elementwise_compare": elementwise_compare,
+    "to_number": to_number,
+    "to_text": to_text,
+    "reverse": reverse,
+    "flatten": flatten,
+    "sum_list": sum_list,
+    "ordered": ordered,
+    "relation_arity": relation_arity,
+    "relation_size": relation_size,
+    "member": member,
+    "length": length,
 }
 
-def evaluate_and_rule(db, and_clauses, binds, subs):
+def float_tolerance(eps):
+    # A unifier for `set_unifier` that treats number values within `eps` of each
+    # other as equal. Only the value position is compared loosely.
+    def tolerant_unify(query, fact, binds={}, global_binds={}):
+        (q_type, q_val), (f_type, f_val) = query[2], fact[2]
+        if q_type == VARIABLE:
+            q_val = get_binds(q_val, binds, global_binds)
+        if isinstance(q_val, (int, float)) and isinstance(f_val, (int, float)) and abs(q_val - f_val) <= eps:
+            query = query[:2] + [fact[2]]
+        return unify(query, fact, binds, global_binds)
+    return tolerant_unify
+
+# `hooks` lets a query watch its own evaluation:
+#   "step":   called with every goal and the bindings it is tried with.
+#   "access": called with an attribute name and "facts" whenever a goal reads
+#             facts, and with a rule name and "rule" whenever a rule is
+#             expanded. Scans over a variable attribute report None.
+def probing(hooks):
+    # Goals that only check or gather what holds (guards, exclusions, count,
+    # collect and the aggregates, the second branch of a difference or
+    # intersection) run with this, so a fully ground goal fails instead of
+    # asserting its missing fact.
+    return dict(hooks, probe=True)
+
+def evaluate_and_rule(db, and_clauses, binds, subs, hooks={}):
     if and_clauses == []:
         yield binds
     else:
         head, *tail = and_clauses
-        possible = evaluate_rule(db, head, binds, subs)
+        possible = evaluate_rule(db, head, binds, subs, hooks)
         for p in possible:
-            yield from evaluate_and_rule(db, tail, p, subs)
+            yield from evaluate_and_rule(db, tail, p, subs, hooks)
 
-def evaluate_rule(db, rule, binds={}, subs={}):
+def evaluate_rule(db, rule, binds={}, subs={}, hooks={}):
     global types
 
     head, *tail = rule
@@ -189,29 +462,46 @@ def evaluate_rule(db, rule, binds={}, subs={}):
     max_args = types[head-6]["arg_count"][1]
     min_args = types[head-6]["arg_count"][0]
     if len(tail) < min_args:
-        raise ValueError("Not enough elements in " + types[head-1]["name"] +\
+        raise ValueError("Not enough elements in " + types[head-6]["name"] +\
                          "! Expected at least "+str(min_args)+", found " + str(len(tail)) + ".")
     elif len(tail) > max_args and max_args != -1:
-        raise ValueError("Too many elements in " + types[head-1]["name"] +\
+        raise ValueError("Too many elements in " + types[head-6]["name"] +\
                          "! Expected less than "+str(max_args)+", found " + str(len(tail)) + ".")
 
-    tail = evaluate_exprs(tail, binds)
+    try:
+        tail = evaluate_exprs(tail, binds)
+    except ArithmeticError:
+        # Division by zero and the like have no value, so the goal fails.
+        return
+
+    if hooks.get("step"):
+        hooks["step"](rule, binds)
 
     if head == PREDICATE:
         was_rule = False
+        sr = None
+        # A variable in the rule position may name the rule to call, so rules
+        # can be written over other rules as well as over attributes.
+        if tail[1][0] == VARIABLE:
+            rule_name = get_binds(tail[1][1], binds, db.global_binds)
+            if isinstance(rule_name, str) and (rule_name in db.rules or rule_name in SPECIAL_RULES):
+                tail[1] = (LITERAL, rule_name)
+        if tail[1][0] == LITERAL and isinstance(tail[1][1], str):
+            tail[1] = (LITERAL, db.resolve_alias(tail[1][1]))
         if tail[1][0] == LITERAL and not (tail[1][1] in db.entities) and (tail[1][1] in db.rules or tail[1][1] in SPECIAL_RULES):
             name = tail[1][1]
             was_rule = True
 
-            tail = [(LITERAL, binds[name])
-                    if tpe == VARIABLE and name in binds
+            tail = [(LITERAL, get_binds(name, binds, db.global_binds))
+                    if tpe == VARIABLE and get_binds(name, binds, db.global_binds) != None
                     else (tpe, name)
                     for tpe, name in tail]
 
-            sr = SPECIAL_RULES.get(name)
+            # The database's own rules win over built-ins of the same name.
+            sr = SPECIAL_RULES.get(name) if not name in db.rules else None
             if sr:
-                res = sr(tail)
-                if res:
+                res = sr(tail, binds, db)
+                if res is not None:
                     yield from res
                 else:
                     yield binds
@@ -221,7 +511,9 @@ def evaluate_rule(db, rule, binds={}, subs={}):
                     raise ValueError("Wrong number of arguments in " + rule["name"].upper() +\
                                      " Rule! Expected "+str(len(rule["args"]))+", found " + str(len(tail)) + ".")
 
-                var_names = [name if tpe == VARIABLE else None for (tpe, name) in tail]
+                # Results go to the end of each argument's variable chain.
+                var_names = [binding_end(name, binds, db.global_binds)[0] if tpe == VARIABLE else None
+                             for (tpe, name) in tail]
                 params = var_names[:1] + var_names[2:]
                 substitutions = dict(zip(rule["args"], params))
 
@@ -230,34 +522,73 @@ def evaluate_rule(db, rule, binds={}, subs={}):
                 input_binds = { k: v for k, v in zip(rule["args"], inputs) if v != None }
 
                 print("STACK DEPTH: " + str(len(inspect.stack())))
-
-                for res in evaluate_rule(db, rule["body"], input_binds, subs=substitutions):
-                    output_binds = { substitutions[key]: value
-                                     for key, value in res.items()
-                                     if key in substitutions and substitutions[key] }
+                if hooks.get("access"):
+                    hooks["access"](name, "rule")
+
+                # The guard only sees the rule's inputs and has to be
+                # satisfiable before the (possibly expensive) body is tried.
+                guard = rule.get("guard")
+                passed = not guard or next(evaluate_rule(db, guard, copy.copy(input_binds), subs=substitutions,
+                                                         hooks=probing(hooks)), None) is not None
+
+                for res in (evaluate_rule(db, rule["body"], input_binds, subs=substitutions, hooks=hooks) if passed else []):
+                    output_binds = { substitutions[key]: resolve(get_binds(key, res, db.global_binds), res, db.global_binds)
+                                     for key in res
+                                     if key in substitutions and substitutions[key] and
+                                        get_binds(key, res, db.global_binds) != None }
                     for key, value in binds.items():
                         if not key in output_binds:
                             output_binds[key] = value
 
+                    if len(tail) == 3:
+                        (a, _, b) = [get_binds(val, output_binds, db.global_binds) if tpe == VARIABLE else val for (tpe, val) in tail]
+                        if db.is_negative(a, name, b):
+                            continue
+
                     yield output_binds
 
-        if not was_rule:
+        # A rule that shares its name with an attribute also matches the
+        # attribute's stored facts, after the rule's own results.
+        if not was_rule or (not sr and tail[1][1] in db.attributes and len(tail) == 3):
             if len(tail) < 3:
                 raise ValueError("Not enough elements in PREDICATE" + \
                                  "! Expected at least 3, found " + str(len(tail)) + ".")
+            if hooks.get("access"):
+                hooks["access"](tail[1][1] if tail[1][0] == LITERAL else None, "facts")
+            fact_unify = unify
+            if tail[1][0] == LITERAL:
+                fact_unify = db.unifiers.get(tail[1][1], unify)
             if tail[0][0] == LITERAL and tail[1][0] == LITERAL and tail[2][0] == LITERAL:
                 res = db.get_value(tail[0][1], tail[1][1])
-                if res and res == tail[2][1]:
-                    yield binds
-                elif not res:
+                if db.is_negative(tail[0][1], tail[1][1], tail[2][1]):
+                    pass
+                elif res != None:
+                    fact = [tail[0], tail[1], ast_value_wrap(res, False)]
+                    if fact_unify(tail, fact, copy.copy(binds), db.global_binds) != None:
+                        yield binds
+                elif not was_rule and not hooks.get("probe"):
                     db.add((tail[0][1], tail[1][1], tail[2][1]))
                     yield binds
             else:
-                for (e, a, v) in db.eavs.values():
+                candidates = db.eavs.values()
+                if tail[0][0] == LITERAL:
+                    # Facts are keyed by entity and attribute, so a known
+                    # entity only needs one lookup per attribute (or just one
+                    # if the attribute is known too) instead of a full scan.
+                    attrs = range(len(db.attributes))
+                    if tail[1][0] == LITERAL:
+                        attrs = [db.attributes.index(tail[1][1])] if tail[1][1] in db.attributes else []
+                    candidates = []
+                    if tail[0][1] in db.entities:
+                        ei = db.entities.index(tail[0][1])
+                        candidates = [f for f in (db.eavs.get(eav_hash(ei, ai)) for ai in attrs) if f]
+                for (e, a, v) in candidates:
+                    if db.is_negative(db.entities[e], db.attributes[a], v):
+                        continue
                     eav_rule = [(LITERAL, db.entities[e]),
                                 (LITERAL, db.attributes[a]),
                                 ast_value_wrap(v, False)]
-                    res = unify(tail, eav_rule, copy.copy(binds), db.global_binds)
+                    res = fact_unify(tail, eav_rule, copy.copy(binds), db.global_binds)
                     if res != None:
                         yield res
     elif head == CONJ_COMP:
@@ -275,17 +606,7 @@ def evaluate_rule(db, rule, binds={}, subs={}):
         failed = False
         last = None
         for v in vals:
-            choice = False
-            if last != None:
-                if op == "<":
-                    choice = v > last
-                elif op == ">":
-                    choice = v < last
-                elif op == ">=":
-                    choice = v <= last
-                elif op == "<=":
-                    choice = v >= last
-            if choice or last is None:
+            if last is None or compare(op, last, v, db.float_tolerance):
                 last = v
             else:
                 failed = True
@@ -294,15 +615,21 @@ def evaluate_rule(db, rule, binds={}, subs={}):
             yield binds
     elif head == UNIFY:
         new_binds = copy.copy(binds)
-        res = unify([tail[0]], [tail[1]], new_binds, db.global_binds)
+        left, right = tail
+        if db.float_tolerance:
+            # Numbers close enough to each other unify as if they were equal.
+            values = [get_binds(v, binds, db.global_binds) if t == VARIABLE else v for (t, v) in tail]
+            if near(values[0], values[1], db.float_tolerance):
+                right = left
+        res = unify([left], [right], new_binds, db.global_binds)
         if res != None:
             yield res
     elif head == CONJ_OR:
         for tail_x in tail:
-            yield from evaluate_rule(db, tail_x, copy.copy(binds), subs)
+            yield from evaluate_rule(db, tail_x, copy.copy(binds), subs, hooks)
     elif head == CONJ_COND:
         for branch in tail:
-            ret = evaluate_rule(db, branch, copy.copy(binds), subs)
+            ret = evaluate_rule(db, branch, copy.copy(binds), subs, hooks)
             try:
                 fst = next(ret)
                 yield from chain([fst], ret)
@@ -310,7 +637,361 @@ def evaluate_rule(db, rule, binds={}, subs={}):
             except StopIteration:
                 continue
     elif head == CONJ_AND:
-        yield from evaluate_and_rule(db, tail, binds, subs)
+        yield from evaluate_and_rule(db, tail, binds, subs, hooks)
+    elif head == CONJ_DIFF or head == CONJ_INTER:
+        # Solutions of the first branch are compared on the variables it
+        # shares with the second one, by trying the second branch with them.
+        keep_matches = head == CONJ_INTER
+        for res in evaluate_rule(db, tail[0], copy.copy(binds), subs, hooks):
+            matched = next(evaluate_rule(db, tail[1], copy.copy(res), subs, probing(hooks)), None) is not None
+            if matched == keep_matches:
+                yield res
+    elif head == CONJ_CASE:
+        (subject_type, subject), *arms = tail
+        if subject_type == VARIABLE:
+            subject_name, subject = subject, get_binds(subject, binds, db.global_binds)
+            if subject == None:
+                raise ValueError("Undefined variable " + subject_name + "!")
+        for (value, branch) in arms:
+            # A None value is the `else` arm.
+            if value == None or same_value(value[1], subject):
+                yield from evaluate_rule(db, branch, binds, subs, hooks)
+                break
+    elif head == CONJ_ONCE:
+        fst = next(evaluate_and_rule(db, tail, binds, subs, hooks), None)
+        if fst is not None:
+            yield fst
+    elif head == CONJ_COUNT:
+        # The goals run as a separate query: only the count comes out of it.
+        result, goals = tail
+        seen = set()
+        for res in evaluate_rule(db, goals, copy.copy(binds), subs, probing(hooks)):
+            seen.add(tuple(sorted((k, freeze(v)) for k, v in res.items())))
+        res = unify([result], [(LITERAL, len(seen))], copy.copy(binds), db.global_binds)
+        if res != None:
+            yield res
+    elif head == CONJ_AGG:
+        # Folds the value of `template` over every solution of the goals. A
+        # solution that leaves it unbound or non-numeric fails the whole
+        # aggregate, and min/max of no solutions have no value.
+        op, (_, template), result, goals = tail
+        values = []
+        for res in evaluate_rule(db, goals, copy.copy(binds), subs, probing(hooks)):
+            value = get_binds(template, res, db.global_binds)
+            if isinstance(value, bool) or not isinstance(value, (int, float)):
+                return
+            values.append(value)
+        if op == "sum":
+            total = sum(values)
+        elif values:
+            total = min(values) if op == "min" else max(values)
+        else:
+            return
+        res = unify([result], [(LITERAL, total)], copy.copy(binds), db.global_binds)
+        if res != None:
+            yield res
+    elif head == CONJ_COLLECT:
+        # A list of `template` filled in by every solution of the goals, in
+        # order and with repeats, e.g. (collect D L (X num N) (> N 2) (= D {N * 2})).
+        # Goals after the generator filter and transform each element. A
+        # solution that leaves a template variable unbound fails the collect.
+        (template_type, template), result, goals = tail
+        values = []
+        for res in evaluate_rule(db, goals, copy.copy(binds), subs, probing(hooks)):
+            value = template
+            if template_type == VARIABLE:
+                value = get_binds(template, res, db.global_binds)
+                if value == None:
+                    return
+            value = resolve(value, res, db.global_binds)
+            values.append((LIST, value) if isinstance(value, list) else (LITERAL, value))
+        res = unify([result], [(LIST, values)], copy.copy(binds), db.global_binds)
+        if res != None:
+            yield res
+
+def project(db, res, variables, unbound):
+    # The values of `variables` in a solution. Variables the solution left
+    # unbound are left out (unbound="skip"), kept as None (unbound="none") or
+    # are an error (unbound="error").
+    if not unbound in ("skip", "none", "error"):
+        raise ValueError("Unknown unbound policy " + str(unbound) + "!")
+    projected = {}
+    for var in variables:
+        value = get_binds(var, res, db.global_binds)
+        if value == None and unbound == "error":
+            raise ValueError("Undefined variable " + var + " in a solution!")
+        if value != None or unbound == "none":
+            projected[var] = value
+    return projected
+
+def query(db, text, binds={}, unbound="skip"):
+    # Runs SEL goals given as text, e.g. query(db, "(X father F) (F age A)"),
+    # yielding each solution with its lists fully resolved. See `project` for
+    # what happens to the variables it left unbound.
+    goals = body(text)[0]
+    for res in evaluate_rule(db, goals, copy.copy(binds)):
+        variables = list(res) + [v for v in rule_variables(goals) if not v in res]
+        yield { k: (resolve(v, res, db.global_binds, (k,)) if v != None else None)
+                for k, v in project(db, res, variables, unbound).items() }
+
+def query_distinct(db, rule, variables, binds={}, unbound="none"):
+    # Solutions projected onto `variables`, skipping any projection already
+    # seen. Other (helper) variables don't make two solutions different.
+    seen = set()
+    for res in evaluate_rule(db, rule, copy.copy(binds)):
+        projected = project(db, res, variables, unbound)
+        key = tuple(freeze(projected.get(var)) for var in variables)
+        if not key in seen:
+            seen.add(key)
+            yield projected
+
+def query_fold(db, rule, init, f, binds={}):
+    # Streams every solution of `rule` through `f`, so that custom reductions
+    # don't need to hold the whole result set in memory.
+    acc = init
+    for res in evaluate_rule(db, rule, copy.copy(binds)):
+        acc = f(acc, res)
+    return acc
+
+def query_sample(db, rule, k, seed=None, binds={}):
+    # Up to `k` solutions picked uniformly at random in one pass (reservoir
+    # sampling), so the whole result set is never held in memory.
+    rng = random.Random(seed)
+    sample = []
+    for i, res in enumerate(evaluate_rule(db, rule, copy.copy(binds))):
+        if i < k:
+            sample.append(res)
+        else:
+            j = rng.randint(0, i)
+            if j < k:
+                sample[j] = res
+    return sample
+
+def query_best_effort(db, rule, binds={}):
+    # Treats the goals of an AND rule as soft: a goal that can't be satisfied
+    # is skipped instead of failing the query, and solutions come back as
+    # (binds, satisfied goal count) pairs, best first.
+    results = []
+
+    def search(goals, binds, satisfied):
+        if goals == []:
+            results.append((binds, satisfied))
+            return
+        head, *tail = goals
+        found = False
+        solutions = evaluate_rule(db, head, copy.copy(binds))
+        while True:
+            try:
+                res = next(solutions)
+            except StopIteration:
+                break
+            except ValueError as err:
+                # A comparison on a variable a skipped goal would have bound
+                # leaves this goal unsatisfied. Anything else is a real error.
+                if not str(err).startswith("Undefined variable"):
+                    raise
+                break
+            found = True
+            search(tail, res, satisfied + 1)
+        if not found:
+            search(tail, binds, satisfied)
+
+    search(rule[1:], copy.copy(binds), 0)
+    return sorted(results, key=lambda r: -r[1])
+
+def query_into_attribute(db, rule, attribute, entity_var, value_var, binds={}):
+    # Solutions are collected first: adding facts while the query is still
+    # scanning `db.eavs` would change the dict under it.
+    facts = []
+    for res in evaluate_rule(db, rule, copy.copy(binds)):
+        entity = get_binds(entity_var, res, db.global_binds)
+        value = get_binds(value_var, res, db.global_binds)
+        if entity == None or value == None:
+            raise ValueError("Undefined variable " + (value_var if entity != None else entity_var) +\
+                             " in a solution for " + attribute + "!")
+        facts.append((entity, attribute, value))
+
+    # An entity only has one value per attribute, so later solutions win.
+    for eav in facts:
+        db.add(eav)
+    return len(facts)
+
+def find_violation(db, domain, invariant, binds={}):
+    # The first solution of `domain` under which `invariant` can't be
+    # satisfied, or None if the invariant holds everywhere.
+    return next(evaluate_rule(db, [CONJ_DIFF, domain, invariant], copy.copy(binds)), None)
+
+def refine_query(db, solutions, rule):
+    # Applies another goal to whatever is left of a running query, without
+    # going back over the solutions that were already taken from it.
+    for binds in solutions:
+        yield from evaluate_rule(db, rule, copy.copy(binds))
+
+def query_require_bound(db, rule, required, binds={}):
+    solutions = []
+    for res in evaluate_rule(db, rule, copy.copy(binds)):
+        project(db, res, required, "error")
+        solutions.append(res)
+    return solutions
+
+def query_rows(db, rule, columns, binds={}, unbound="error"):
+    # Solutions as tuples of the `columns` variables, in order. Each column
+    # names the type from `db.type_name` its values must have. A row can't
+    # leave a column out, so unbound="skip" drops the whole solution.
+    rows = []
+    for res in evaluate_rule(db, rule, copy.copy(binds)):
+        projected = project(db, res, [var for (var, _) in columns], unbound)
+        if any(not var in projected for (var, _) in columns):
+            continue
+        row = []
+        for (var, type_name) in columns:
+            value = projected[var]
+            if value != None:
+                actual = "list" if isinstance(value, list) else db.type_name[db.value_type(value)]
+                if actual != type_name:
+                    raise TypeError("Wrong type for column " + var + ". Expected " + type_name +\
+                                    ", got " + actual + " (" + value_text(value) + ")!")
+            row.append(value)
+        rows.append(tuple(row))
+    return rows
+
+def query_traced(db, rule, on_step, binds={}):
+    return list(evaluate_rule(db, rule, copy.copy(binds), hooks={ "step": on_step }))
+
+class BudgetExceeded(Exception):
+    pass
+
+def query_with_budget(db, rule, max_steps, binds={}):
+    # Gives up once `max_steps` goals have been tried, so rules that recurse
+    # forever still return. Returns the solutions found so far and whether
+    # the search finished.
+    steps = [0]
+    def count(goal, binds):
+        steps[0] += 1
+        if steps[0] > max_steps:
+            raise BudgetExceeded()
+
+    solutions = []
+    try:
+        for res in evaluate_rule(db, rule, copy.copy(binds), hooks={ "step": count }):
+            solutions.append(res)
+        return (solutions, True)
+    except BudgetExceeded:
+        return (solutions, False)
+
+def query_audited(db, rule, on_access, binds={}):
+    return list(evaluate_rule(db, rule, copy.copy(binds), hooks={ "access": on_access }))
+
+def substitute(node, binds, global_binds={}):
+    # Replaces every bound variable in a rule AST with its value.
+    if isinstance(node, (list, tuple)):
+        if len(node) == 2 and node[0] == VARIABLE and isinstance(node[1], str):
+            val = get_binds(node[1], binds, global_binds)
+            if val == None:
+                return node
+            return (LIST, val) if isinstance(val, list) else (LITERAL, val)
+        return type(node)(substitute(n, binds, global_binds) for n in node)
+    return node
+
+def rule_variables(node):
+    # The local variables of a rule AST, in the order they first appear.
+    if isinstance(node, (list, tuple)):
+        if len(node) == 2 and node[0] == VARIABLE and isinstance(node[1], str):
+            return [] if node[1][0] == '*' else [node[1]]
+        names = []
+        for n in node:
+            names += [name for name in rule_variables(n) if not name in names]
+        return names
+    return []
+
+def resolve(value, binds, global_binds={}, seen=()):
+    # Fills in the variables left inside a bound list, all the way down, and
+    # follows variables linked to other variables. Variables that are unbound
+    # (or would loop back on themselves) are kept, as the last variable of
+    # their chain.
+    if isinstance(value, tuple):
+        end, bound = binding_end(value[1], binds, global_binds)
+        if bound == None or end in seen:
+            return (VARIABLE, end)
+        return resolve(bound, binds, global_binds, seen + (end,))
+    if not isinstance(value, list):
+        return value
+    resolved = []
+    for (tpe, val) in value:
+        if tpe == VARIABLE:
+            end, bound = binding_end(val, binds, global_binds)
+            if bound == None or val in seen or end in seen:
+                resolved.append((VARIABLE, end))
+                continue
+            inner = resolve(bound, binds, global_binds, seen + (val, end))
+            resolved.append((LIST, inner) if isinstance(inner, list) else (LITERAL, inner))
+        elif isinstance(val, list):
+            resolved.append((tpe, resolve(val, binds, global_binds, seen)))
+        else:
+            resolved.append((tpe, val))
+    return resolved
+
+def resolve_binds(binds, global_binds={}):
+    return { k: resolve(v, binds, global_binds, (k,)) for k, v in binds.items() }
+
+def ground_rule(db, name, limit=1000):
+    # The rule's body once for every way the facts can satisfy it, with all
+    # its variables filled in. Stops after `limit` instances.
+    rule = db.rules[name]
+    grounded = []
+    for res in evaluate_rule(db, rule["body"], {}):
+        if len(grounded) >= limit:
+            break
+        grounded.append(substitute(rule["body"], res, db.global_binds))
+    return grounded
+
+def rule_calls(node, db, negative=False):
+    # (rule name, negative) for every rule called in a rule AST. A call is
+    # negative when the caller depends on it failing or on all of its
+    # solutions: the second branch of a difference, the fallback branches of
+    # a conditional, and the goals of count, collect and the other aggregates.
+    calls = []
+    if isinstance(node, (list, tuple)) and node:
+        if node[0] == EXPR and len(node) == 2:
+            return []
+        elif node[0] == CONJ_COND:
+            return rule_calls(node[1], db, negative) + sum((rule_calls(n, db, True) for n in node[2:]), [])
+        elif node[0] == CONJ_COUNT or node[0] == CONJ_AGG or node[0] == CONJ_COLLECT:
+            return rule_calls(node[-1], db, True)
+        elif node[0] == PREDICATE and len(node) > 2 and node[2][0] == LITERAL and isinstance(node[2][1], str):
+            name = db.resolve_alias(node[2][1])
+            if name in db.rules:
+                calls.append((name, negative))
+        elif node[0] == CONJ_DIFF:
+            return rule_calls(node[1], db, negative) + rule_calls(node[2], db, True)
+        for child in node:
+            calls += rule_calls(child, db, negative)
+    return calls
+
+def canonical_rule(rule):
+    # The rule's args, guard and body with every (non-global) variable,
+    # including those inside expressions, renamed by order of first
+    # appearance, so rules that only differ in variable names compare equal.
+    # Loaded rules have lists where parsed ones have tuples, so both are
+    # accepted and frozen into tuples.
+    names = {}
+
+    def rename(name):
+        if name[0] == '*':
+            return name
+        return names.setdefault(name, "_" + str(len(names)))
+
+    def walk(node):
+        if isinstance(node, (list, tuple)):
+            if len(node) == 2 and node[0] == VARIABLE and isinstance(node[1], str):
+                return (VARIABLE, rename(node[1]))
+            if len(node) == 2 and node[0] == EXPR and isinstance(node[1], (list, tuple)):
+                return (EXPR, tuple(rename(t) if isinstance(t, str) and t[:1].isupper() else t for t in node[1]))
+            return tuple(walk(n) for n in node)
+        return node
+
+    args = tuple(rename(a) for a in rule["args"] or [])
+    return (args, walk(rule.get("guard")), walk(rule["body"]))
 
 def clean_symbol(e):
     if isinstance(e, Symbol):
@@ -329,6 +1010,10 @@ def create_datatype(e, entities):
             return (LITERAL, entities[number])
         else:
             return (LITERAL, e)
+    elif e == "true" or e == "false":
+        # Quoted strings became ENTITY_ placeholders above, so only the bare
+        # symbols get here.
+        return (LITERAL, e == "true")
     elif is_variable(e):
         return (VARIABLE, e)
     else:
@@ -349,7 +1034,41 @@ def create_rule(lst, entities):
         rule.append(CONJ_COND)
         for r in lst[1:]:
             rule.append(create_rule(r, entities))
-    elif lst[0] in ["<", ">", "<=", ">="]:
+    elif lst[0] == "-":
+        rule.append(CONJ_DIFF)
+        for r in lst[1:]:
+            rule.append(create_rule(r, entities))
+    elif lst[0] == "^":
+        rule.append(CONJ_INTER)
+        for r in lst[1:]:
+            rule.append(create_rule(r, entities))
+    elif lst[0] == "case":
+        rule.append(CONJ_CASE)
+        rule.append(create_datatype(lst[1], entities))
+        for arm in lst[2:]:
+            value, *goals = [clean_symbol(sym) for sym in arm]
+            rule.append([None if value == "else" else create_datatype(value, entities),
+                         [CONJ_AND] + [create_rule(r, entities) for r in goals]])
+    elif lst[0] == "count":
+        rule.append(CONJ_COUNT)
+        rule.append(create_datatype(lst[1], entities))
+        rule.append([CONJ_AND] + [create_rule(r, entities) for r in lst[2:]])
+    elif lst[0] in ["sum", "min", "max"]:
+        rule.append(CONJ_AGG)
+        rule.append(lst[0])
+        rule.append(create_datatype(lst[1], entities))
+        rule.append(create_datatype(lst[2], entities))
+        rule.append([CONJ_AND] + [create_rule(r, entities) for r in lst[3:]])
+    elif lst[0] == "collect":
+        rule.append(CONJ_COLLECT)
+        rule.append(create_datatype(lst[1], entities))
+        rule.append(create_datatype(lst[2], entities))
+        rule.append([CONJ_AND] + [create_rule(r, entities) for r in lst[3:]])
+    elif lst[0] == "once":
+        rule.append(CONJ_ONCE)
+        for r in lst[1:]:
+            rule.append(create_rule(r, entities))
+    elif lst[0] in ["<", ">", "<=", ">=", "!="]:
         rule.append(CONJ_COMP)
         rule.append(lst[0])
         rule.extend(create_rule(lst[1:], entities)[1:])
@@ -378,6 +1097,44 @@ def body(st):
     new_body, entities = create_text_entities("(& " + st + ")")
     return create_rule(loads(new_body), entities), st
 
+class MaintainedAggregate:
+    # The count, sum, min or max of an attribute's values, kept up to date by
+    # the database as facts are added and removed instead of being recomputed
+    # by a query. Values that aren't numbers are only counted.
+    def __init__(self, op, attribute):
+        self.op = op
+        self.attribute = attribute
+        self.reset()
+
+    def reset(self):
+        self.count = 0
+        self.total = 0
+        self.counts = {}
+        self.extreme = None
+
+    def update(self, value, change):
+        self.count += change
+        if isinstance(value, bool) or not isinstance(value, (int, float)):
+            return
+        self.total += change * value
+        self.counts[value] = self.counts.get(value, 0) + change
+        if self.counts[value] == 0:
+            del self.counts[value]
+
+        pick = min if self.op == "min" else max
+        if change > 0:
+            self.extreme = value if self.extreme is None else pick(self.extreme, value)
+        elif value == self.extreme and not value in self.counts:
+            # The extreme itself went, so the next one has to be found.
+            self.extreme = pick(self.counts) if self.counts else None
+
+    def value(self):
+        if self.op == "count":
+            return self.count
+        elif self.op == "sum":
+            return self.total
+        return self.extreme
+
 #  _____    ___     __  ____        _        _
 # | ____|  / \ \   / / |  _ \  __ _| |_ __ _| |__   __ _ ___  ___
 # |  _|   / _ \ \ / /  | | | |/ _` | __/ _` | '_ \ / _` / __|/ _ \
@@ -392,21 +1149,61 @@ class EAVDatabase:
         self.eavs = {}
         self.global_binds = {}
         self.rules = {}
-        self.type_name = ["entity", "string", "int", "float"]
+        self.aliases = {}
+        self.negatives = []
+        self.exclusions = []
+        self.float_tolerance = 0
         if args != {}:
             args["eavs"] = { float(k): v for k, v in args["eavs"].items() }
             self.__dict__.update(args)
+        # Not taken from saved files, so older ones pick up new types.
+        self.type_name = ["entity", "string", "int", "float", "bool"]
+        # Functions can't be saved, so unifiers are set up again after loading.
+        # Maintained aggregates are too.
+        self.unifiers = {}
+        self.aggregates = []
+        # `negatives` by entity and attribute, as they're checked for every
+        # fact a goal looks at. Rebuilt from the list rather than saved.
+        self.negative_values = {}
+        for (entity, attr, value) in self.negatives:
+            self.negative_values.setdefault((freeze(entity), attr), []).append(value)
+
+    def saved_fields(self):
+        return { k: v for k, v in self.__dict__.items() if not k in ("unifiers", "aggregates", "negative_values") }
+
+    # Makes facts of `attr` match with something other than plain `unify`.
+    # A unifier takes the same arguments as `unify` (the predicate, the fact,
+    # the bindings and the global bindings) and returns the new bindings or
+    # None. It should only ever accept a fact that `unify` would reject by
+    # design, e.g. numbers that are close enough, and must still bind the
+    # query's variables. None removes the attribute's unifier.
+    def set_unifier(self, attr, unifier):
+        attr = self.resolve_alias(attr)
+        if unifier == None:
+            self.unifiers.pop(attr, None)
+        else:
+            self.unifiers[attr] = unifier
 
-    def validate(self, data, attr, value):
-        actual_type = 1
+    # Numbers at most `eps` apart are equal to `=` and `!=` goals. Facts are
+    # still matched exactly unless their attribute has a `float_tolerance`
+    # unifier, and <, >, <= and >= aren't affected.
+    def set_float_tolerance(self, eps):
+        self.float_tolerance = eps
+
+    def value_type(self, value):
+        # Index into `type_name`. Anything unrecognised counts as a string.
         if isinstance(value, str) and value in self.entities:
-            actual_type = 0
-        elif isinstance(value, str):
-            actual_type = 1
+            return 0
+        elif isinstance(value, bool):
+            return 4
         elif isinstance(value, int):
-            actual_type = 2
+            return 2
         elif isinstance(value, float):
-            actual_type = 3
+            return 3
+        return 1
+
+    def validate(self, data, attr, value):
+        actual_type = self.value_type(value)
 
         if data["type"] != actual_type:
             raise TypeError("Wrong type for " + attr + ". Expected " + self.type_name[data["type"]] +\
@@ -416,7 +1213,8 @@ class EAVDatabase:
             data["type"] == 1 and isinstance(value, str) and (not data["allowed_strings"] or\
                                                                 value in data["allowed_strings"] or\
                                                                 len(data["allowed_strings"]) == 0) or\
-            (data["type"] == 2 or data["type"] == 3) and between_limits(value, data["num_limits"])
+            (data["type"] == 2 or data["type"] == 3) and between_limits(value, data["num_limits"]) or\
+            data["type"] == 4
 
         custom_message = ""
         if data["type"] == 0:
@@ -432,19 +1230,91 @@ class EAVDatabase:
                             self.type_name[data["type"]] + custom_message + ", got: " + str(value) + ".")
 
     def change_attribute_metadata(self, attr, new):
-        self.attribute_metadata[attr] = new
+        self.attribute_metadata[self.resolve_alias(attr)] = new
 
-    def add_rule(self, name, rule_args=[], new_rule={
-            "lang": 0,
-            "text": "",
-            "body": ""
-        }):
+    def add_alias(self, name, existing):
+        if name in self.attributes or name in self.rules:
+            raise ValueError("Can't alias " + name + ", it is already an attribute or a rule.")
+        self.aliases[name] = existing
+        try:
+            self.resolve_alias(name)
+        except ValueError:
+            del self.aliases[name]
+            raise
+
+    def resolve_alias(self, name):
+        seen = []
+        while name in self.aliases:
+            if name in seen:
+                raise ValueError("Alias " + name + " refers back to itself through " + ", ".join(seen) + ".")
+            seen.append(name)
+            name = self.aliases[name]
+        return name
+
+    def add_rule(self, name, rule_args=[], new_rule=None):
+        if new_rule == None:
+            new_rule = { "lang": 0, "text": "", "body": "" }
+        # The rules window re-adds every rule it draws, so a guard set
+        # earlier has to survive being saved without one.
+        old_rule = self.rules.get(name, {})
+        if not "guard" in new_rule and old_rule.get("guard"):
+            new_rule["guard"] = old_rule["guard"]
+            new_rule["guard_text"] = old_rule.get("guard_text", "")
         new_rule.update({
             "name": name,
             "args": rule_args,
         })
         self.rules[name] = new_rule
 
+    def add_rule_text(self, name, rule_args, text, guard_text=None):
+        # A guard_text of None keeps the rule's current guard, "" removes it.
+        try:
+            rule_body, rule_text = body(text)
+            new_rule = {
+                "lang": 0,
+                "text": rule_text,
+                "body": rule_body
+            }
+            if guard_text != None:
+                new_rule["guard"] = body(guard_text)[0] if guard_text else None
+                new_rule["guard_text"] = guard_text
+        except Exception as e:
+            raise ValueError("Could not parse the body of rule " + name + ": " + str(e)) from e
+
+        self.add_rule(name, rule_args, new_rule)
+
+    def check_stratification(self):
+        # Groups of rules that can be evaluated in order, each group only
+        # depending on itself and the groups before it. A rule that depends on
+        # itself negatively (see `rule_calls`) has no well-defined answer.
+        edges, negative = {}, set()
+        for name, rule in self.rules.items():
+            calls = rule_calls(rule.get("body"), self) + rule_calls(rule.get("guard"), self)
+            edges[name] = [callee for (callee, _) in calls]
+            negative |= { (name, callee) for (callee, neg) in calls if neg }
+
+        strata = strongly_connected(edges)
+        for stratum in strata:
+            for (caller, callee) in negative:
+                if caller in stratum and callee in stratum:
+                    raise ValueError("Rules " + ", ".join(stratum) + " depend on each other through a negation or an aggregate!")
+        return strata
+
+    def duplicate_rules(self):
+        # Rules that are a renamed copy of an earlier one. Rules that haven't
+        # been given a body yet aren't counted.
+        seen = []
+        duplicates = []
+        for name, rule in self.rules.items():
+            if not rule.get("body"):
+                continue
+            canonical = canonical_rule(rule)
+            if canonical in seen:
+                duplicates.append(name)
+            else:
+                seen.append(canonical)
+        return duplicates
+
     def get_or_add_entity_id(self, entity):
         forein_entity = -1
         try:
@@ -464,39 +1334,163 @@ class EAVDatabase:
         return forein_attr
 
     def add(self, eav):
+        if len(eav) != 3:
+            raise ValueError("A fact needs an entity, an attribute and a value, got " + str(eav) + "!")
         (entity, attr, value) = eav
+        if not isinstance(attr, str):
+            raise TypeError("Attributes are names, got " + str(attr) + "!")
+        attr = self.resolve_alias(attr)
+        entity_count, attribute_count = len(self.entities), len(self.attributes)
         forein_entity = self.get_or_add_entity_id(entity)
         forein_attr = self.get_or_add_attribute_id(attr)
 
         if attr in self.attribute_metadata:
             data = self.attribute_metadata[attr]
 
-            if not data.get("is_list"):
-                self.validate(data, attr, value)
+            try:
+                if not data.get("is_list"):
+                    self.validate(data, attr, value)
+                else:
+                    for v in value:
+                        self.validate(data, attr, v[1])
+            except:
+                del self.entities[entity_count:]
+                del self.attributes[attribute_count:]
+                raise
+
+        h = eav_hash(forein_entity, forein_attr)
+        previous = self.eavs.get(h)
+        self.eavs[h] = (forein_entity, forein_attr, value)
+
+        broken = self.broken_exclusion()
+        if broken:
+            if previous:
+                self.eavs[h] = previous
             else:
-                for v in value:
-                    self.validate(data, attr, v[1])
+                del self.eavs[h]
+            del self.entities[entity_count:]
+            del self.attributes[attribute_count:]
+            raise ValueError("Adding " + str(eav) + " breaks the exclusion: " + broken["text"])
+
+        if previous:
+            self.fact_changed(attr, previous[2], -1)
+        self.fact_changed(attr, value, 1)
+        return self
 
-        self.eavs[eav_hash(forein_entity, forein_attr)] = (forein_entity, forein_attr, value)
+    # Keeps the count, sum, min or max of `attribute`'s values up to date from
+    # now on. The returned aggregate's `value()` is the current result; min
+    # and max of no numbers are None.
+    def maintain_aggregate(self, op, attribute):
+        if not op in ["count", "sum", "min", "max"]:
+            raise ValueError("Unknown aggregate " + str(op) + "!")
+        aggregate = MaintainedAggregate(op, self.resolve_alias(attribute))
+        self.aggregates.append(aggregate)
+        self.recompute_aggregates([aggregate])
+        return aggregate
+
+    def recompute_aggregates(self, aggregates):
+        for aggregate in aggregates:
+            aggregate.reset()
+            if aggregate.attribute in self.attributes:
+                for (e, a, v) in self.get_entities_values(aggregate.attribute):
+                    aggregate.update(v, 1)
+
+    def fact_changed(self, attr, value, change):
+        for aggregate in self.aggregates:
+            if aggregate.attribute == attr:
+                aggregate.update(value, change)
+
+    # Exclusions are goals that must never be satisfiable together, e.g. an
+    # entity being both alive and deceased. Any `add` that would make one
+    # satisfiable is undone and raises instead.
+    def add_exclusion(self, text):
+        try:
+            exclusion_body, exclusion_text = body(text)
+        except Exception as e:
+            raise ValueError("Could not parse the exclusion: " + str(e)) from e
+        exclusion = { "text": exclusion_text, "body": exclusion_body }
+
+        if next(evaluate_rule(self, exclusion_body, {}, hooks=probing({})), None) is not None:
+            raise ValueError("The database already breaks the exclusion: " + exclusion_text)
+        self.exclusions.append(exclusion)
+        return self
 
+    def broken_exclusion(self):
+        for exclusion in self.exclusions:
+            if next(evaluate_rule(self, exclusion["body"], {}, hooks=probing({})), None) is not None:
+                return exclusion
+        return None
+
+    # Negative facts hide any matching fact, whether it is stored or derived
+    # by a rule, without deleting it. A negative always wins over a positive.
+    def add_negative(self, eav):
+        (entity, attr, value) = (eav[0], self.resolve_alias(eav[1]), eav[2])
+        if not self.is_negative(entity, attr, value):
+            self.negatives.append([entity, attr, value])
+            self.negative_values.setdefault((freeze(entity), attr), []).append(value)
         return self
 
+    def remove_negative(self, eav):
+        (entity, attr, value) = (eav[0], self.resolve_alias(eav[1]), eav[2])
+        self.negatives = [n for n in self.negatives
+                          if not (n[0] == entity and n[1] == attr and same_value(n[2], value))]
+        values = [v for v in self.negative_values.get((freeze(entity), attr), []) if not same_value(v, value)]
+        if values:
+            self.negative_values[(freeze(entity), attr)] = values
+        else:
+            self.negative_values.pop((freeze(entity), attr), None)
+
+    def is_negative(self, entity, attr, value):
+        return any(same_value(v, value) for v in self.negative_values.get((freeze(entity), attr), []))
+
     def remove_value(self, entity, attr):
+        attr = self.resolve_alias(attr)
         try:
             h = eav_hash(self.entities.index(entity), self.attributes.index(attr))
-            if h in self.eavs: del self.eavs[h]
+            if h in self.eavs:
+                self.fact_changed(attr, self.eavs.pop(h)[2], -1)
         except:
             print("Entity or Attribute " + str((entity, attr)) + " not found in database.")
 
+    def remove_fact(self, eav):
+        # Only removes the fact if the entity has exactly that value. The
+        # entity and attribute stay known even when their last fact goes.
+        (entity, attr, value) = eav
+        attr = self.resolve_alias(attr)
+        if self.get_value(entity, attr) != value:
+            return False
+        h = eav_hash(self.entities.index(entity), self.attributes.index(attr))
+        self.fact_changed(attr, self.eavs.pop(h)[2], -1)
+        return True
+
+    def remove_rule(self, name):
+        return self.rules.pop(name, None) != None
+
     def get_attributes_values(self, entity):
         e = self.entities.index(entity)
         return (eav for (h, eav) in self.eavs.items() if eav[0] == e)
 
     def get_entities_values(self, attribute):
-        ai = self.attributes.index(attribute)
+        ai = self.attributes.index(self.resolve_alias(attribute))
         return (eav for (h, eav) in self.eavs.items() if eav[1] == ai)
 
+    def suggest_values(self, attribute, prefix="", position=1):
+        # Distinct entities (position 0) or values (position 1) of an
+        # attribute for autocompletion. Only text is matched against `prefix`.
+        attribute = self.resolve_alias(attribute)
+        if not attribute in self.attributes:
+            return []
+        found = []
+        for (e, a, v) in self.get_entities_values(attribute):
+            value = self.entities[e] if position == 0 else v
+            if prefix and not (isinstance(value, str) and value.startswith(prefix)):
+                continue
+            if not value in found:
+                found.append(value)
+        return sorted(found, key=sort_key)
+
     def get_value(self, entity, attr):
+        attr = self.resolve_alias(attr)
         if not (entity in self.entities) or (not attr in self.attributes):
             return None
         h = eav_hash(self.entities.index(entity), self.attributes.index(attr))
@@ -505,6 +1499,34 @@ class EAVDatabase:
         else:
             return None
 
+    def fact_sets(self):
+        facts = {}
+        for (e, a, v) in self.eavs.values():
+            facts.setdefault(self.attributes[a], set()).add((self.entities[e], freeze(v)))
+        return facts
+
+    def diff(self, other):
+        # Facts (as entity/value pairs per attribute) and rule names that
+        # `other` has and this database doesn't are "added", the reverse are
+        # "removed". Rules in both whose definitions differ are "changed".
+        mine, theirs = self.fact_sets(), other.fact_sets()
+        result = { "added": {}, "removed": {}, "rules_added": [], "rules_removed": [], "rules_changed": [] }
+        for attr in set(mine) | set(theirs):
+            added = theirs.get(attr, set()) - mine.get(attr, set())
+            removed = mine.get(attr, set()) - theirs.get(attr, set())
+            if added:
+                result["added"][attr] = sorted(added, key=str)
+            if removed:
+                result["removed"][attr] = sorted(removed, key=str)
+
+        for name in other.rules:
+            if not name in self.rules:
+                result["rules_added"].append(name)
+            elif canonical_rule(self.rules[name]) != canonical_rule(other.rules[name]):
+                result["rules_changed"].append(name)
+        result["rules_removed"] = [name for name in self.rules if not name in other.rules]
+        return result
+
     def create_hashmaps_data(self):
         data = []
         for (entity, attribute, value) in sorted(self.eavs.values(), key=lambda x: x[0]):
@@ -524,6 +1546,88 @@ class EAVDatabase:
 
         return data
 
+    def json_facts(self, entity, obj, facts):
+        for (attr, value) in obj.items():
+            if attr == "entity":
+                continue
+            if isinstance(value, dict):
+                # Nested objects become entities of their own, named after
+                # the path that leads to them.
+                child = entity + "/" + attr
+                self.json_facts(child, value, facts)
+                facts.append((entity, attr, child))
+            else:
+                facts.append((entity, attr, json_value(value, entity, attr)))
+        return facts
+
+    def load_json(self, data):
+        if not isinstance(data, list):
+            raise ValueError("Expected a JSON array of objects, got a " + type(data).__name__ + ".")
+
+        facts = []
+        for obj in data:
+            if not isinstance(obj, dict) or not isinstance(obj.get("entity"), str):
+                raise ValueError("Every JSON object needs a string \"entity\" field, got: " + str(obj) + ".")
+            self.json_facts(obj["entity"], obj, facts)
+
+        # Either every fact goes in or none do.
+        entities, attributes, eavs = list(self.entities), list(self.attributes), dict(self.eavs)
+        try:
+            for eav in facts:
+                self.add(eav)
+        except:
+            self.entities, self.attributes, self.eavs = entities, attributes, eavs
+            self.recompute_aggregates(self.aggregates)
+            raise
+        return len(facts)
+
+    def is_functional(self, key_attributes, value_attributes):
+        # Entities missing one of the key attributes don't take part.
+        key_attributes = [self.resolve_alias(a) for a in key_attributes]
+        value_attributes = [self.resolve_alias(a) for a in value_attributes]
+        seen = {}
+        for entity in self.entities:
+            key = tuple(freeze(self.get_value(entity, a)) for a in key_attributes)
+            if None in key:
+                continue
+            value = tuple(freeze(self.get_value(entity, a)) for a in value_attributes)
+            if seen.setdefault(key, value) != value:
+                return False
+        return True
+
+    def sccs(self, attribute):
+        # Strongly connected components of the graph with an edge from each
+        # entity to its value for `attribute`.
+        edges = {}
+        for (e, a, v) in self.get_entities_values(attribute):
+            edges.setdefault(self.entities[e], []).append(freeze(v))
+            edges.setdefault(freeze(v), [])
+        return strongly_connected(edges)
+
+    def to_dot(self, attributes):
+        attributes = [self.resolve_alias(a) for a in attributes]
+        nodes = []
+        edges = []
+        for (entity, attribute, value) in self.eavs.values():
+            attr = self.attributes[attribute]
+            if not attr in attributes:
+                continue
+            # List values don't have a single node to point at.
+            if isinstance(value, list):
+                edges.append("    // skipped list value of " + attr + " for " + self.entities[entity])
+                continue
+
+            for node in (self.entities[entity], value):
+                if not node in nodes:
+                    nodes.append(node)
+            edges.append("    " + json.dumps(str(self.entities[entity])) + " -> " + json.dumps(str(value)) +\
+                         " [label=" + json.dumps(attr) + "];")
+
+        return "\n".join(["digraph {"] +
+                         ["    " + json.dumps(str(n)) + ";" for n in nodes] +
+                         edges +
+                         ["}"])
+
     def load_examples(self):
         (
             self.add(("cool@gmail.com", "name", "Joe Cool"))
@@ -555,12 +1659,56 @@ class EAVDatabase:
             .add(("mammam_cool@gmail.com", "age", 59))
         )
 
+def json_value(value, entity, attr, in_list=False):
+    if value is None or isinstance(value, dict):
+        raise ValueError("Unsupported JSON value for " + attr + " of " + entity + ": " + json.dumps(value) + ".")
+    elif isinstance(value, list):
+        lst = [json_value(v, entity, attr, True) for v in value]
+        return (LIST, lst) if in_list else lst
+    else:
+        return (LITERAL, value) if in_list else value
+
 def save_to_file(db, name):
     print("Saved to: " + name)
     outfile = open(os.path.expanduser(os.path.expandvars(name)),'w')
-    json.dump(db.__dict__, outfile)
+    json.dump(db.saved_fields(), outfile)
+    outfile.close()
+
+def save_to_binary(db, name):
+    # The same data as `save_to_file`, zlib-compressed. Unlike a pickle,
+    # loading it can't run code, so files from anywhere are safe to open.
+    print("Saved to: " + name)
+    outfile = open(os.path.expanduser(os.path.expandvars(name)),'wb')
+    outfile.write(zlib.compress(json.dumps(db.saved_fields(), separators=(',', ':')).encode("utf-8")))
     outfile.close()
 
+def load_from_binary(name):
+    print("Load from: " + name)
+    infile = open(os.path.expanduser(os.path.expandvars(name)),'rb')
+    db = EAVDatabase(**json.loads(zlib.decompress(infile.read()).decode("utf-8")))
+    infile.close()
+    return db
+
+def from_parts(facts, rules={}, attribute_metadata={}):
+    # Builds a database from { attribute: [(entity, value), ...] } and
+    # { rule name: (args, SEL text) }. Unlike EAVDatabase(**args), every fact
+    # goes through `add` and every rule is parsed, so bad input raises.
+    db = EAVDatabase()
+    db.attribute_metadata = copy.deepcopy(attribute_metadata)
+    for attr, pairs in facts.items():
+        for pair in pairs:
+            if len(pair) != 2:
+                raise ValueError("Facts for " + str(attr) + " need an entity and a value, got " + str(pair) + "!")
+            db.add((pair[0], attr, pair[1]))
+
+    for name, (args, text) in rules.items():
+        if name in db.attributes:
+            raise ValueError("Rule " + name + " has the same name as an attribute!")
+        if not all(isinstance(a, str) and is_variable(a) for a in args) or len(set(args)) != len(args):
+            raise ValueError("The arguments of rule " + name + " must be distinct variables, got " + str(args) + "!")
+        db.add_rule_text(name, list(args), text)
+    return db
+
 def load_from_file(name):
     print("Load from: " + name)
     infile = open(os.path.expanduser(os.path.expandvars(name)),'r')
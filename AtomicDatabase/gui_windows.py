@@ -86,6 +86,8 @@ def draw_data_popup(DB, constant=False):
             data_value = 0
         elif changed and data_type == 1:
             data_value = ""
+        elif changed and data_type == 4:
+            data_value = False
         if data_type == 0:
             changed, data_value = imgui.combo(
                 "Value##data-value-entity",
@@ -109,6 +111,8 @@ def draw_data_popup(DB, constant=False):
                 'Value##data-value-float',
                 data_value
             )
+        elif data_type == 4:
+            changed, data_value = imgui.checkbox('Value##data-value-bool', data_value)
         if imgui.button("OK") or imgui.get_io().keys_down[SDL_SCANCODE_RETURN]:
             try:
                 if data_type == 0:
@@ -159,6 +163,10 @@ def draw_eav_value(DB, ent, att, v, metadata={}):
             )
             if changed:
                 return (ent, att, DB.entities[new_entity])
+        elif (metadata and metadata["type"] == 4) or (not metadata and isinstance(v, bool)):
+            changed, new_value = imgui.checkbox(iden, v)
+            if changed:
+                return (ent, att, new_value)
         elif (metadata and metadata["type"] == 2) or (not metadata and isinstance(v, int)):
             changed, new_value = imgui.input_int(iden, v)
             if changed:
@@ -243,6 +251,8 @@ def draw_imgui_attribute_metadata(DB):
                 metadata["allowed_strings"] = list(filter(lambda x: len(x), strings.split(",")))
             elif metadata["type"] == 0:
                 imgui.text("All entities allowed")
+            elif metadata["type"] == 4:
+                imgui.text("Either true or false")
             imgui.unindent()
         if imgui.button("Add New Attribute Metadata"):
             imgui.open_popup("new-attribute-meta")
@@ -384,6 +394,10 @@ def draw_imgui_eav_database(DB):
         imgui.end()
 
 
+# Lists in query results are cut down to this many elements for display, -1
+# shows them whole.
+query_list_width = 10
+
 def draw_query(binds):
     imgui.columns(2, "QueryBinds")
 
@@ -397,7 +411,7 @@ def draw_query(binds):
         imgui.text(str(k))
         imgui.next_column()
         if isinstance(v, list):
-            imgui.text(str([e[1] for e in v]))
+            imgui.text(str(eav.truncate_list([e[1] for e in v], query_list_width)))
         else:
             imgui.text(str(v))
         imgui.next_column()
@@ -480,7 +494,7 @@ def draw_imgui_query_box(DB, monospace_font):
         new_query_result = False
         print("--- NEXT")
         try:
-            query_binds = next(query_result)
+            query_binds = eav.resolve_binds(next(query_result), DB.global_binds)
             print("Query Binds: " + str(query_binds))
         except StopIteration:
             print("No more results")
@@ -23,17 +23,66 @@ def between_limits(value, limits):
     (a,b) = limits
     return (value >= a or a == -1) and (value <= b or b == -1)
 
+def list_values(lst):
+    return [list_values(v) if isinstance(v, list) else v for (_, v) in lst]
+
+def same_value(a, b):
+    # Bools are ints to Python, but true isn't the same value as 1, in lists
+    # as much as anywhere else.
+    if isinstance(a, list) and isinstance(b, list):
+        return len(a) == len(b) and all(same_value(x[1], y[1]) for (x, y) in zip(a, b))
+    return a == b and isinstance(a, bool) == isinstance(b, bool)
+
+def near(a, b, eps):
+    # Numbers (not booleans) at most `eps` apart.
+    numbers = all(isinstance(v, (int, float)) and not isinstance(v, bool) for v in (a, b))
+    return numbers and abs(a - b) <= eps
+
+def compare(op, a, b, eps=0):
+    # Lists compare element by element (a prefix is less than the whole
+    # list), and only with other lists. Booleans (false before true) also
+    # only order among themselves. Numbers within `eps` aren't unequal.
+    if isinstance(a, bool) != isinstance(b, bool):
+        return op == "!="
+    if isinstance(a, list) or isinstance(b, list):
+        if not (isinstance(a, list) and isinstance(b, list)):
+            return op == "!="
+        a, b = list_values(a), list_values(b)
+    if op == "<":
+        return a < b
+    elif op == ">":
+        return a > b
+    elif op == "<=":
+        return a <= b
+    elif op == ">=":
+        return a >= b
+    elif op == "!=":
+        return a != b and not (eps and near(a, b, eps))
+    else:
+        raise ValueError("Unknown comparison " + str(op) + "!")
+
 def eav_hash(a, b):
     return 0.5*(a + b)*(a + b + 1)+b
 
 def eval_expr(val, binds):
     return eval(" ".join([str(v) for v in val]), {}, binds)
 
+def binding_end(name, binds, global_binds):
+    # A variable unified with another unbound variable is bound to it as a
+    # (VARIABLE, name) tuple. Follows those links to the last variable in the
+    # chain and its value, treating a chain that loops back as unbound.
+    seen = set()
+    while True:
+        value = global_binds.get(name) if name[0] == '*' else binds.get(name)
+        if not isinstance(value, tuple):
+            return name, value
+        if name in seen:
+            return name, None
+        seen.add(name)
+        name = value[1]
+
 def get_binds(name, binds, global_binds):
-    if name[0] == '*':
-        return global_binds.get(name)
-    else:
-        return binds.get(name)
+    return binding_end(name, binds, global_binds)[1]
 
 def peek(iterable):
     try:
@@ -53,6 +102,60 @@ def limit_format(obj):
     else:
         return "<any string>"
 
+def freeze(value):
+    # A hashable copy of a value, with lists (and wrapped list elements)
+    # turned into tuples.
+    if isinstance(value, (list, tuple)):
+        return tuple(freeze(v) for v in value)
+    return value
+
+def strongly_connected(edges):
+    # Tarjan's algorithm. `edges` maps every node to its successors; the
+    # components come out with each one after all the components it reaches.
+    index, lowlink, stack, on_stack, components = {}, {}, [], set(), []
+
+    def connect(node):
+        index[node] = lowlink[node] = len(index)
+        stack.append(node)
+        on_stack.add(node)
+        for succ in edges[node]:
+            if not succ in index:
+                connect(succ)
+                lowlink[node] = min(lowlink[node], lowlink[succ])
+            elif succ in on_stack:
+                lowlink[node] = min(lowlink[node], index[succ])
+
+        if lowlink[node] == index[node]:
+            component = []
+            while True:
+                member = stack.pop()
+                on_stack.remove(member)
+                component.append(member)
+                if member == node:
+                    break
+            components.append(component)
+
+    for node in edges:
+        if not node in index:
+            connect(node)
+    return components
+
+def sort_key(value):
+    # Orders any two values: booleans, numbers, then text, then lists
+    # (element by element), with the usual order within each kind.
+    if isinstance(value, list):
+        return (3, [sort_key(v[1]) for v in value])
+    elif isinstance(value, str):
+        return (2, value)
+    elif isinstance(value, bool):
+        return (0, value)
+    return (1, value)
+
+def truncate_list(lst, width):
+    if width == -1 or len(lst) <= width:
+        return lst
+    return lst[:width] + ["... (" + str(len(lst) - width) + " more)"]
+
 def is_variable(e):
     return isinstance(e, str) and e[0].upper() == e[0] and not e[0].isnumeric() and not " " in e
 